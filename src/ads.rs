@@ -0,0 +1,414 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::sync::Arc;
+
+use futures::channel::mpsc;
+use futures::future::{self, Ready};
+use futures::{SinkExt, StreamExt};
+use hyper::body::Incoming;
+use hyper::header::CONTENT_TYPE;
+use hyper::{Request, Response};
+use log::{info, warn};
+use tokio::sync::broadcast::error::RecvError;
+use tonic::body::BoxBody;
+use tonic::codec::{ProstCodec, Streaming};
+use tonic::server::{Grpc, StreamingService};
+use tonic::Status;
+use uuid::Uuid;
+
+use super::server::{build_cluster_load_assignments, cluster_key};
+use super::types::{Config, Storage};
+use super::v2xds::{self, build_version_info, Locality};
+use super::v3xds;
+use super::watch::{Watch, WatchedStorage};
+use super::xdsproto::{ClusterLoadAssignment, DiscoveryRequest, DiscoveryResponse};
+
+const V2_ADS_PATH: &str =
+    "/envoy.service.discovery.v2.AggregatedDiscoveryService/StreamAggregatedResources";
+const V3_ADS_PATH: &str =
+    "/envoy.service.discovery.v3.AggregatedDiscoveryService/StreamAggregatedResources";
+const V2_EDS_PATH: &str = "/envoy.api.v2.EndpointDiscoveryService/StreamEndpoints";
+const V3_EDS_PATH: &str = "/envoy.service.endpoint.v3.EndpointDiscoveryService/StreamEndpoints";
+
+pub fn is_grpc<B>(req: &Request<B>) -> bool {
+    req.headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("application/grpc"))
+}
+
+// Serves StreamAggregatedResources and StreamEndpoints of both v2 and v3. Only EDS resources are
+// served; requests for other types are ignored.
+pub async fn serve<S: Storage>(
+    s: WatchedStorage<S>,
+    c: Arc<Config>,
+    req: Request<Incoming>,
+) -> Response<BoxBody> {
+    let default_type_url = match req.uri().path() {
+        V2_ADS_PATH | V3_ADS_PATH => None,
+        V2_EDS_PATH => Some(v2xds::EDS_TYPE_URL),
+        V3_EDS_PATH => Some(v3xds::EDS_TYPE_URL),
+        path => return Status::unimplemented(format!("Unknown method: {}", path)).into_http(),
+    };
+    let service = Streams {
+        s,
+        c,
+        default_type_url,
+    };
+    Grpc::new(ProstCodec::default())
+        .streaming(service, req)
+        .await
+}
+
+struct Streams<S: Storage> {
+    s: WatchedStorage<S>,
+    c: Arc<Config>,
+    // The type of requests without type_url, which only StreamEndpoints allows.
+    default_type_url: Option<&'static str>,
+}
+
+impl<S: Storage> StreamingService<DiscoveryRequest> for Streams<S> {
+    type Response = DiscoveryResponse;
+    type ResponseStream = mpsc::Receiver<Result<DiscoveryResponse, Status>>;
+    type Future = Ready<Result<tonic::Response<Self::ResponseStream>, Status>>;
+
+    fn call(&mut self, req: tonic::Request<Streaming<DiscoveryRequest>>) -> Self::Future {
+        let (tx, rx) = mpsc::channel(1);
+        tokio::spawn(run_stream(
+            self.s.clone(),
+            self.c.clone(),
+            self.default_type_url,
+            req.into_inner(),
+            tx,
+        ));
+        future::ready(Ok(tonic::Response::new(rx)))
+    }
+}
+
+// The resources a stream subscribes to for a type, and the response sent last.
+struct Subscription {
+    resource_names: BTreeSet<String>,
+    // Storage keys of the resources, to match changes with.
+    keys: HashSet<String>,
+    // Keep the keys polled while subscribed.
+    watches: Vec<Watch>,
+    locality: Option<Locality>,
+    version_info: Option<String>,
+    nonce: Option<String>,
+}
+
+// Responds to every change of subscriptions, and pushes new assignments when a subscribed
+// service is written through this instance or when its poller finds the content changed.
+async fn run_stream<S: Storage>(
+    s: WatchedStorage<S>,
+    c: Arc<Config>,
+    default_type_url: Option<&'static str>,
+    mut requests: Streaming<DiscoveryRequest>,
+    mut tx: mpsc::Sender<Result<DiscoveryResponse, Status>>,
+) {
+    let mut changes = s.subscribe();
+    let mut subscriptions: HashMap<&'static str, Subscription> = HashMap::new();
+
+    loop {
+        // The types to respond to, and whether to respond even when the content is unchanged.
+        let (type_urls, force): (Vec<&'static str>, bool) = tokio::select! {
+            req = requests.next() => {
+                let d_req = match req {
+                    Some(Ok(v)) => v,
+                    // Usually the client has gone away.
+                    Some(Err(status)) => {
+                        info!("Close stream: {}", status);
+                        return;
+                    }
+                    None => return,
+                };
+                match update_subscription(&mut subscriptions, &c, default_type_url, d_req) {
+                    Some(type_url) => {
+                        if let Some(sub) = subscriptions.get_mut(type_url) {
+                            // Watch the new keys before dropping the old watches, so that the
+                            // pollers of keys still subscribed keep running.
+                            let watches = sub.keys.iter().map(|key| s.watch(key)).collect();
+                            sub.watches = watches;
+                        }
+                        (vec![type_url], true)
+                    }
+                    None => continue,
+                }
+            }
+            change = changes.recv() => match change {
+                Ok(key) => (
+                    subscriptions
+                        .iter()
+                        .filter(|(_, sub)| sub.keys.contains(&key))
+                        .map(|(type_url, _)| *type_url)
+                        .collect(),
+                    false,
+                ),
+                Err(RecvError::Lagged(_)) => (subscriptions.keys().cloned().collect(), false),
+                Err(RecvError::Closed) => continue,
+            },
+        };
+
+        for type_url in type_urls {
+            let sub = match subscriptions.get_mut(type_url) {
+                Some(v) => v,
+                None => continue,
+            };
+            match build_response(&s, &c, type_url, sub, force).await {
+                Ok(Some(d_res)) => {
+                    if tx.send(Ok(d_res)).await.is_err() {
+                        // The client has gone away.
+                        return;
+                    }
+                }
+                Ok(None) => {}
+                // Retried when the poller finds a change or the storage recovers.
+                Err(e) => warn!(
+                    "Failed to build EDS response: type_url={}, error={}",
+                    type_url, e
+                ),
+            }
+        }
+    }
+}
+
+// Returns the type to respond to, or None for ACKs, NACKs and requests to ignore.
+fn update_subscription(
+    subscriptions: &mut HashMap<&'static str, Subscription>,
+    c: &Config,
+    default_type_url: Option<&'static str>,
+    d_req: DiscoveryRequest,
+) -> Option<&'static str> {
+    let node_id = d_req.node.as_ref().map_or("", |n| n.id.as_str());
+    if let Some(ref status) = d_req.error_detail {
+        warn!(
+            "Receive NACK: node={}, version_info={}, response_nonce={}, code={}, message={}",
+            node_id, d_req.version_info, d_req.response_nonce, status.code, status.message
+        );
+    }
+    let type_url = match (d_req.type_url.as_str(), default_type_url) {
+        ("", Some(t)) => t,
+        (v2xds::EDS_TYPE_URL, _) => v2xds::EDS_TYPE_URL,
+        (v3xds::EDS_TYPE_URL, _) => v3xds::EDS_TYPE_URL,
+        (t, _) => {
+            warn!(
+                "Ignore request of unsupported type_url: node={}, type_url={}",
+                node_id, t
+            );
+            return None;
+        }
+    };
+
+    let resource_names: BTreeSet<String> = d_req.resource_names.into_iter().collect();
+    // Invalid names are answered with empty assignments, since a stream cannot reject one
+    // resource alone.
+    let keys = resource_names
+        .iter()
+        .filter_map(|n| {
+            let key = cluster_key(c, n);
+            if key.is_none() {
+                warn!("Ignore invalid cluster name: node={}, name={}", node_id, n);
+            }
+            key
+        })
+        .collect();
+    match subscriptions.get_mut(type_url) {
+        Some(sub) => {
+            // Requests answering an older response are superseded by ones answering the latest.
+            if !d_req.response_nonce.is_empty()
+                && sub.nonce.as_deref() != Some(d_req.response_nonce.as_str())
+            {
+                return None;
+            }
+            if sub.resource_names == resource_names {
+                return None;
+            }
+            sub.resource_names = resource_names;
+            sub.keys = keys;
+        }
+        None => {
+            info!(
+                "Open EDS subscription: node={}, type_url={}, resource_names={:?}",
+                node_id, type_url, resource_names
+            );
+            // Envoy only sends its node in the first request of a stream.
+            let locality = d_req.node.and_then(|n| n.locality).map(Locality::from);
+            subscriptions.insert(
+                type_url,
+                Subscription {
+                    resource_names,
+                    keys,
+                    watches: Vec::new(),
+                    locality,
+                    version_info: None,
+                    nonce: None,
+                },
+            );
+        }
+    }
+    Some(type_url)
+}
+
+// Returns None when the content is the same as the one sent last and the response is not forced.
+async fn build_response<S: Storage>(
+    s: &WatchedStorage<S>,
+    c: &Config,
+    type_url: &'static str,
+    sub: &mut Subscription,
+    force: bool,
+) -> Result<Option<DiscoveryResponse>, String> {
+    let names: Vec<String> = sub.resource_names.iter().cloned().collect();
+    let resources =
+        build_cluster_load_assignments(s, c, None, &names, sub.locality.as_ref(), type_url)
+            .await
+            .map_err(|e| e.to_string())?;
+    let version_info = build_version_info(&resources).map_err(|e| e.to_string())?;
+    if !force && sub.version_info.as_ref() == Some(&version_info) {
+        return Ok(None);
+    }
+
+    let mut any_resources = Vec::new();
+    for r in resources.iter() {
+        let cla = ClusterLoadAssignment::from_json(r).map_err(|e| e.to_string())?;
+        any_resources.push(cla.to_any(type_url));
+    }
+    let nonce = Uuid::new_v4().to_string();
+    info!(
+        "Push EDS response: type_url={}, resources={}, version_info={}, nonce={}",
+        type_url,
+        any_resources.len(),
+        version_info,
+        nonce
+    );
+    sub.version_info = Some(version_info.clone());
+    sub.nonce = Some(nonce.clone());
+    Ok(Some(DiscoveryResponse {
+        version_info,
+        resources: any_resources,
+        type_url: type_url.to_owned(),
+        nonce,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use super::*;
+    use crate::types::{CdsConfig, EdsConfig};
+    use crate::xdsproto::Status as RpcStatus;
+
+    fn build_config() -> Config {
+        Config {
+            bind_addr: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            listen_port: 0,
+            env: "production".to_owned(),
+            cds: CdsConfig::default(),
+            eds: EdsConfig::default(),
+            auth: None,
+            tls: None,
+        }
+    }
+
+    fn discovery_request(names: &[&str], response_nonce: &str) -> DiscoveryRequest {
+        DiscoveryRequest {
+            version_info: String::new(),
+            node: None,
+            resource_names: names.iter().map(|n| (*n).to_owned()).collect(),
+            type_url: v3xds::EDS_TYPE_URL.to_owned(),
+            response_nonce: response_nonce.to_owned(),
+            error_detail: None,
+        }
+    }
+
+    // Subscribes to names as if the response with nonce "1" has been sent.
+    fn subscribe(names: &[&str]) -> HashMap<&'static str, Subscription> {
+        let mut subscriptions = HashMap::new();
+        let d_req = discovery_request(names, "");
+        let type_url = update_subscription(&mut subscriptions, &build_config(), None, d_req);
+        assert_eq!(type_url, Some(v3xds::EDS_TYPE_URL));
+        subscriptions.get_mut(v3xds::EDS_TYPE_URL).unwrap().nonce = Some("1".to_owned());
+        subscriptions
+    }
+
+    fn subscribed_names(subscriptions: &HashMap<&'static str, Subscription>) -> Vec<String> {
+        subscriptions[v3xds::EDS_TYPE_URL]
+            .resource_names
+            .iter()
+            .cloned()
+            .collect()
+    }
+
+    #[test]
+    fn ignore_acks_and_nacks() {
+        let c = build_config();
+        let mut subscriptions = subscribe(&["user", "team/user"]);
+        let keys = &subscriptions[v3xds::EDS_TYPE_URL].keys;
+        assert!(keys.contains("user") && keys.contains("team/user"));
+
+        let ack = discovery_request(&["team/user", "user"], "1");
+        assert_eq!(update_subscription(&mut subscriptions, &c, None, ack), None);
+
+        let mut nack = discovery_request(&["user", "team/user"], "1");
+        nack.error_detail = Some(RpcStatus {
+            code: 3,
+            message: "rejected".to_owned(),
+        });
+        assert_eq!(
+            update_subscription(&mut subscriptions, &c, None, nack),
+            None
+        );
+        assert_eq!(subscribed_names(&subscriptions), ["team/user", "user"]);
+    }
+
+    #[test]
+    fn ignore_requests_with_stale_nonce() {
+        let c = build_config();
+        let mut subscriptions = subscribe(&["user"]);
+        let d_req = discovery_request(&["payment"], "0");
+        assert_eq!(
+            update_subscription(&mut subscriptions, &c, None, d_req),
+            None
+        );
+        assert_eq!(subscribed_names(&subscriptions), ["user"]);
+    }
+
+    #[test]
+    fn update_changed_names() {
+        let c = build_config();
+        let mut subscriptions = subscribe(&["user"]);
+        let d_req = discovery_request(&["payment", "user@staging"], "1");
+        assert_eq!(
+            update_subscription(&mut subscriptions, &c, None, d_req),
+            Some(v3xds::EDS_TYPE_URL)
+        );
+        assert_eq!(
+            subscribed_names(&subscriptions),
+            ["payment", "user@staging"]
+        );
+        // Invalid names are kept to be answered, but never watched.
+        let keys: Vec<&String> = subscriptions[v3xds::EDS_TYPE_URL].keys.iter().collect();
+        assert_eq!(keys, ["payment"]);
+    }
+
+    #[test]
+    fn ignore_unsupported_type_url() {
+        let c = build_config();
+        let mut subscriptions = HashMap::new();
+        let mut d_req = discovery_request(&["user"], "");
+        d_req.type_url = "type.googleapis.com/envoy.config.cluster.v3.Cluster".to_owned();
+        assert_eq!(
+            update_subscription(&mut subscriptions, &c, None, d_req),
+            None
+        );
+        assert!(subscriptions.is_empty());
+
+        // StreamEndpoints may omit type_url.
+        let mut d_req = discovery_request(&["user"], "");
+        d_req.type_url = String::new();
+        let default_type_url = Some(v2xds::EDS_TYPE_URL);
+        assert_eq!(
+            update_subscription(&mut subscriptions, &c, default_type_url, d_req),
+            Some(v2xds::EDS_TYPE_URL)
+        );
+    }
+}
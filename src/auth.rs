@@ -0,0 +1,402 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bytes::Bytes;
+use hmac::{Hmac, Mac};
+use hyper::header::AUTHORIZATION;
+use hyper::Request;
+use serde_derive::Deserialize;
+use sha2::{Digest, Sha256};
+
+// How far the timestamp of a signed request may be from the clock of sds, in seconds.
+const MAX_CLOCK_SKEW_SECS: u64 = 300;
+
+// A client authenticated by one of the tokens, put into request extensions.
+#[derive(Debug, Clone)]
+pub struct Principal {
+    pub id: String,
+    services: Vec<String>,
+}
+
+impl Principal {
+    // Patterns are either an exact name or a prefix ending with '*'. Namespaced services are
+    // matched by "namespace/service". A prefix never reaches across the namespace separator, so
+    // "*" only matches services without a namespace and "team/*" only the ones in "team".
+    pub fn can_write(&self, name: &str) -> bool {
+        self.services.iter().any(|p| match p.strip_suffix('*') {
+            Some(prefix) => name.starts_with(prefix) && !name[prefix.len()..].contains('/'),
+            None => p == name,
+        })
+    }
+}
+
+// Verifies the credentials of one scheme of the Authorization header. Another scheme is added by
+// implementing this and registering it in Auth::load.
+pub trait Authenticator: Send + Sync {
+    // Compared case-insensitively, like "Bearer".
+    fn scheme(&self) -> &'static str;
+    // Takes the credentials following the scheme. Returns the reason for invalid ones.
+    fn authenticate(&self, credentials: &str, req: &Request<Bytes>) -> Result<Principal, String>;
+}
+
+#[derive(Deserialize)]
+struct TokensFile {
+    tokens: Vec<Token>,
+}
+
+#[derive(Deserialize)]
+struct Token {
+    id: String,
+    secret: String,
+    // Patterns of the services the token may write.
+    services: Vec<String>,
+}
+
+impl Token {
+    fn to_principal(&self) -> Principal {
+        Principal {
+            id: self.id.to_owned(),
+            services: self.services.clone(),
+        }
+    }
+}
+
+// `Authorization: Bearer <secret>`
+struct BearerTokens {
+    tokens: Arc<Vec<Token>>,
+}
+
+impl Authenticator for BearerTokens {
+    fn scheme(&self) -> &'static str {
+        "Bearer"
+    }
+
+    fn authenticate(&self, credentials: &str, _: &Request<Bytes>) -> Result<Principal, String> {
+        self.tokens
+            .iter()
+            .find(|t| constant_time_eq(t.secret.as_bytes(), credentials.as_bytes()))
+            .map(Token::to_principal)
+            .ok_or_else(|| "Unknown bearer token".to_owned())
+    }
+}
+
+// `Authorization: HMAC-SHA256 id=<token id>,ts=<epoch seconds>,sig=<hex>`, where sig is the
+// HMAC-SHA256 of "<method>\n<path and query>\n<ts>\n<hex SHA-256 of the body>" keyed by the secret.
+struct HmacSignatures {
+    tokens: Arc<Vec<Token>>,
+    // Signatures accepted within the allowed skew, with their ts. A signature is accepted only
+    // once, so that a captured request cannot be replayed to this instance while its ts is valid.
+    seen: Mutex<HashMap<Vec<u8>, u64>>,
+}
+
+impl HmacSignatures {
+    fn new(tokens: Arc<Vec<Token>>) -> HmacSignatures {
+        HmacSignatures {
+            tokens,
+            seen: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Returns false when the signature has been accepted before.
+    fn accept_once(&self, sig: Vec<u8>, ts: u64, now: u64) -> bool {
+        let mut seen = self.seen.lock().unwrap_or_else(|e| e.into_inner());
+        // Signatures older than the skew are rejected by their ts anyway.
+        seen.retain(|_, seen_ts| now.saturating_sub(*seen_ts) <= MAX_CLOCK_SKEW_SECS);
+        seen.insert(sig, ts).is_none()
+    }
+}
+
+impl Authenticator for HmacSignatures {
+    fn scheme(&self) -> &'static str {
+        "HMAC-SHA256"
+    }
+
+    fn authenticate(&self, credentials: &str, req: &Request<Bytes>) -> Result<Principal, String> {
+        let mut id = None;
+        let mut ts = None;
+        let mut sig = None;
+        for param in credentials.split(',') {
+            match param.trim().split_once('=') {
+                Some(("id", v)) => id = Some(v),
+                Some(("ts", v)) => ts = Some(v),
+                Some(("sig", v)) => sig = Some(v),
+                _ => return Err(format!("Malformed signature parameter: {}", param)),
+            }
+        }
+        let (id, ts, sig) = match (id, ts, sig) {
+            (Some(id), Some(ts), Some(sig)) => (id, ts, sig),
+            _ => return Err("Signature requires id, ts and sig".to_owned()),
+        };
+
+        let token = self
+            .tokens
+            .iter()
+            .find(|t| t.id == id)
+            .ok_or_else(|| format!("Unknown token id: {}", id))?;
+        let ts_secs: u64 = ts
+            .parse()
+            .map_err(|_| format!("ts must be epoch seconds: {}", ts))?;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(|_| "Could not fetch system time".to_owned())?
+            .as_secs();
+        if ts_secs.max(now) - ts_secs.min(now) > MAX_CLOCK_SKEW_SECS {
+            return Err(format!("ts is too far from now: {}", ts));
+        }
+
+        let path_and_query = req
+            .uri()
+            .path_and_query()
+            .map(|pq| pq.as_str())
+            .unwrap_or("/");
+        let string_to_sign = format!(
+            "{}\n{}\n{}\n{}",
+            req.method(),
+            path_and_query,
+            ts,
+            hex::encode(Sha256::digest(req.body()))
+        );
+        let sig = hex::decode(sig).map_err(|_| "sig must be hex".to_owned())?;
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(token.secret.as_bytes()).map_err(|e| e.to_string())?;
+        mac.update(string_to_sign.as_bytes());
+        mac.verify_slice(&sig)
+            .map_err(|_| "Signature does not match".to_owned())?;
+        if !self.accept_once(sig, ts_secs, now) {
+            return Err("Signature has already been used".to_owned());
+        }
+        Ok(token.to_principal())
+    }
+}
+
+#[derive(Clone)]
+pub struct Auth {
+    authenticators: Vec<Arc<dyn Authenticator>>,
+    // Also require a valid token to read registrations and to call EDS and CDS.
+    pub protect_reads: bool,
+}
+
+impl fmt::Debug for Auth {
+    // Never print the secrets.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let schemes: Vec<&str> = self.authenticators.iter().map(|a| a.scheme()).collect();
+        f.debug_struct("Auth")
+            .field("schemes", &schemes)
+            .field("protect_reads", &self.protect_reads)
+            .finish()
+    }
+}
+
+impl Auth {
+    // Loads the tokens from a JSON file like `{"tokens": [{"id", "secret", "services"}]}`.
+    pub fn load(path: &str, protect_reads: bool) -> Result<Auth, String> {
+        let content = fs::read_to_string(path).map_err(|e| e.to_string())?;
+        let file: TokensFile = serde_json::from_str(&content).map_err(|e| e.to_string())?;
+        Ok(Auth::new(file.tokens, protect_reads))
+    }
+
+    fn new(tokens: Vec<Token>, protect_reads: bool) -> Auth {
+        let tokens = Arc::new(tokens);
+        Auth {
+            authenticators: vec![
+                Arc::new(BearerTokens {
+                    tokens: tokens.clone(),
+                }),
+                Arc::new(HmacSignatures::new(tokens)),
+            ],
+            protect_reads,
+        }
+    }
+
+    // Returns None for requests without credentials, which are rejected later if the route
+    // requires them.
+    pub fn authenticate(&self, req: &Request<Bytes>) -> Result<Option<Principal>, String> {
+        let header = match req.headers().get(AUTHORIZATION) {
+            Some(v) => v
+                .to_str()
+                .map_err(|_| "Authorization header must be visible ASCII".to_owned())?,
+            None => return Ok(None),
+        };
+        let header = header.trim();
+        let (scheme, credentials) = header.split_once(' ').unwrap_or((header, ""));
+        match self
+            .authenticators
+            .iter()
+            .find(|a| a.scheme().eq_ignore_ascii_case(scheme))
+        {
+            Some(a) => a.authenticate(credentials.trim(), req).map(Some),
+            None => Err(format!("Unsupported authorization scheme: {}", scheme)),
+        }
+    }
+}
+
+// Does not return early on the first differing byte, so that the time taken does not tell how
+// much of a guessed secret is right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn build_auth() -> Auth {
+        Auth::new(
+            vec![Token {
+                id: "deployer".to_owned(),
+                secret: "s3cret".to_owned(),
+                services: vec!["user".to_owned(), "pay*".to_owned(), "team-a/*".to_owned()],
+            }],
+            false,
+        )
+    }
+
+    fn now_secs() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    fn sign(method: &str, path: &str, ts: u64, body: &[u8]) -> String {
+        let string_to_sign = format!(
+            "{}\n{}\n{}\n{}",
+            method,
+            path,
+            ts,
+            hex::encode(Sha256::digest(body))
+        );
+        let mut mac = Hmac::<Sha256>::new_from_slice(b"s3cret").unwrap();
+        mac.update(string_to_sign.as_bytes());
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    fn build_request(
+        method: &str,
+        path: &str,
+        authorization: Option<&str>,
+        body: &str,
+    ) -> Request<Bytes> {
+        let mut builder = Request::builder().method(method).uri(path);
+        if let Some(v) = authorization {
+            builder = builder.header(AUTHORIZATION, v);
+        }
+        builder.body(Bytes::from(body.to_owned())).unwrap()
+    }
+
+    fn build_signed_request(method: &str, path: &str, ts: u64, body: &str) -> Request<Bytes> {
+        let sig = sign(method, path, ts, body.as_bytes());
+        let header = format!("HMAC-SHA256 id=deployer,ts={},sig={}", ts, sig);
+        build_request(method, path, Some(&header), body)
+    }
+
+    #[test]
+    fn can_write_patterns_within_namespace() {
+        let p = build_auth().authenticate(&build_request("GET", "/", Some("Bearer s3cret"), ""));
+        let p = p.unwrap().unwrap();
+        assert!(p.can_write("user"));
+        assert!(!p.can_write("user2"));
+        assert!(p.can_write("payment"));
+        assert!(p.can_write("team-a/payment"));
+        assert!(!p.can_write("team-b/user"));
+        // Prefixes do not reach into namespaces.
+        assert!(!p.can_write("pay/user"));
+        assert!(!p.can_write("team-a/sub/user"));
+
+        let any = Principal {
+            id: "admin".to_owned(),
+            services: vec!["*".to_owned()],
+        };
+        assert!(any.can_write("user"));
+        assert!(!any.can_write("team-a/user"));
+    }
+
+    #[test]
+    fn authenticate_bearer_tokens() {
+        let auth = build_auth();
+        let p = auth.authenticate(&build_request("GET", "/", Some("Bearer s3cret"), ""));
+        assert_eq!(p.unwrap().unwrap().id, "deployer");
+        // Schemes are case-insensitive.
+        let p = auth.authenticate(&build_request("GET", "/", Some("bearer  s3cret "), ""));
+        assert_eq!(p.unwrap().unwrap().id, "deployer");
+        assert!(auth
+            .authenticate(&build_request("GET", "/", Some("Bearer s3cre"), ""))
+            .is_err());
+    }
+
+    #[test]
+    fn authenticate_without_or_with_unknown_schemes() {
+        let auth = build_auth();
+        assert!(auth
+            .authenticate(&build_request("GET", "/", None, ""))
+            .unwrap()
+            .is_none());
+        assert!(auth
+            .authenticate(&build_request("GET", "/", Some("Basic czNjcmV0"), ""))
+            .is_err());
+        assert!(auth
+            .authenticate(&build_request("GET", "/", Some("Bearer"), ""))
+            .is_err());
+    }
+
+    #[test]
+    fn authenticate_signatures() {
+        let auth = build_auth();
+        let now = now_secs();
+        let req = build_signed_request("POST", "/v1/registration/user?env=prod", now, "{}");
+        assert_eq!(auth.authenticate(&req).unwrap().unwrap().id, "deployer");
+
+        // The signature covers the method, the query and the body.
+        let sig = sign("POST", "/v1/registration/user", now - 1, b"{}");
+        let header = format!("HMAC-SHA256 id=deployer,ts={},sig={}", now - 1, sig);
+        for (method, path, body) in [
+            ("DELETE", "/v1/registration/user", "{}"),
+            ("POST", "/v1/registration/user?env=prod", "{}"),
+            ("POST", "/v1/registration/user", "{\"ip\":1}"),
+        ] {
+            let req = build_request(method, path, Some(&header), body);
+            assert!(auth.authenticate(&req).is_err());
+        }
+
+        let req = build_request("POST", "/", Some("HMAC-SHA256 id=deployer,sig=00"), "");
+        assert!(auth.authenticate(&req).is_err());
+        let req = build_signed_request("POST", "/", now, "");
+        let header = req.headers()[AUTHORIZATION]
+            .to_str()
+            .unwrap()
+            .replace("deployer", "other");
+        let req = build_request("POST", "/", Some(&header), "");
+        assert!(auth.authenticate(&req).is_err());
+    }
+
+    #[test]
+    fn reject_signatures_far_from_now() {
+        let auth = build_auth();
+        let now = now_secs();
+        for ts in [
+            now - MAX_CLOCK_SKEW_SECS - 10,
+            now + MAX_CLOCK_SKEW_SECS + 10,
+        ] {
+            let req = build_signed_request("DELETE", "/v1/registration/user/10.0.0.1/80", ts, "");
+            assert!(auth.authenticate(&req).is_err());
+        }
+        let req = build_signed_request("DELETE", "/v1/registration/user/10.0.0.1/80", now - 10, "");
+        assert!(auth.authenticate(&req).is_ok());
+    }
+
+    #[test]
+    fn reject_replayed_signatures() {
+        let auth = build_auth();
+        let req = build_signed_request(
+            "DELETE",
+            "/v1/registration/user/10.0.0.1/80",
+            now_secs(),
+            "",
+        );
+        assert!(auth.authenticate(&req).is_ok());
+        assert!(auth.authenticate(&req).is_err());
+    }
+}
@@ -0,0 +1,499 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use futures::future::{self, FutureExt, Shared};
+use log::{info, warn};
+
+use super::types::{HealthStatus, Host, RevisionWeights, ServiceSummary, Storage, StorageFuture};
+
+// Serves hosts and revision weights of each service and the list of services from memory for
+// `ttl` instead of querying the underlying storage every time. Local writes invalidate the entries
+// of the service, but writes through other sds instances are only visible after `ttl`.
+#[derive(Clone)]
+pub struct CachedStorage<S: Storage> {
+    inner: S,
+    ttl: Duration,
+    // Serve the last known hosts when the underlying storage fails.
+    serve_stale_on_error: bool,
+    slots: Arc<Mutex<ServiceSlots<S::E>>>,
+    // Listing services scans the whole storage, which CDS does on every poll.
+    services: Arc<Mutex<Slot<Vec<ServiceSummary>, S::E>>>,
+}
+
+struct ServiceSlots<E> {
+    slots: HashMap<String, ServiceSlot<E>>,
+    // The generation of new slots. Kept above the ones of evicted slots, so that a query started
+    // for an evicted slot never fills the slot created again for the same service.
+    next_generation: u64,
+    evicted_at: Instant,
+}
+
+impl<E> ServiceSlots<E> {
+    fn new() -> ServiceSlots<E> {
+        ServiceSlots {
+            slots: HashMap::new(),
+            next_generation: 0,
+            evicted_at: Instant::now(),
+        }
+    }
+
+    fn get_or_insert(&mut self, name: &str) -> &mut ServiceSlot<E> {
+        let generation = self.next_generation;
+        self.slots
+            .entry(name.to_owned())
+            .or_insert_with(|| ServiceSlot::new(generation))
+    }
+
+    // Removes slots of services nobody queries anymore, at most once per `ttl`. Names queried
+    // once, like ones of deleted services, would otherwise be kept forever.
+    fn evict(&mut self, ttl: Duration, keep_stale: bool) {
+        if self.evicted_at.elapsed() < ttl {
+            return;
+        }
+        self.evicted_at = Instant::now();
+        let mut next_generation = self.next_generation;
+        self.slots.retain(|_, slot| {
+            let evictable = slot.hosts.is_evictable(ttl, keep_stale, Vec::is_empty)
+                && slot
+                    .weights
+                    .is_evictable(ttl, keep_stale, RevisionWeights::is_empty);
+            if evictable {
+                next_generation = next_generation
+                    .max(slot.hosts.generation + 1)
+                    .max(slot.weights.generation + 1);
+            }
+            !evictable
+        });
+        self.next_generation = next_generation;
+    }
+}
+
+// EDS queries both for every cluster it serves.
+struct ServiceSlot<E> {
+    hosts: Slot<Vec<Host>, E>,
+    weights: Slot<RevisionWeights, E>,
+}
+
+impl<E> ServiceSlot<E> {
+    fn new(generation: u64) -> ServiceSlot<E> {
+        ServiceSlot {
+            hosts: Slot::new(generation),
+            weights: Slot::new(generation),
+        }
+    }
+}
+
+struct Slot<T, E> {
+    entry: Option<CacheEntry<T>>,
+    // The query running for this slot. Concurrent misses wait for it instead of issuing another
+    // query.
+    in_flight: Option<Shared<StorageFuture<T, E>>>,
+    // Bumped on every invalidation, so that a query started before a local write does not
+    // overwrite the entry with outdated hosts.
+    generation: u64,
+}
+
+struct CacheEntry<T> {
+    value: T,
+    fetched_at: Instant,
+}
+
+impl<T, E> Slot<T, E> {
+    fn new(generation: u64) -> Slot<T, E> {
+        Slot {
+            entry: None,
+            in_flight: None,
+            generation,
+        }
+    }
+
+    // Slots without a query running are evictable once expired. Stale values are kept to be
+    // served on errors unless they are empty.
+    fn is_evictable(&self, ttl: Duration, keep_stale: bool, is_empty: impl Fn(&T) -> bool) -> bool {
+        if self.in_flight.is_some() {
+            return false;
+        }
+        match self.entry {
+            Some(ref e) if e.fetched_at.elapsed() < ttl => false,
+            Some(ref e) => !keep_stale || is_empty(&e.value),
+            None => true,
+        }
+    }
+
+    fn invalidate(&mut self) {
+        self.entry = None;
+        self.in_flight = None;
+        self.generation += 1;
+    }
+}
+
+impl<S> CachedStorage<S>
+where
+    S: Storage,
+    S::E: Clone + Sync,
+{
+    pub fn new(inner: S, ttl: Duration, serve_stale_on_error: bool) -> CachedStorage<S> {
+        CachedStorage {
+            inner,
+            ttl,
+            serve_stale_on_error,
+            slots: Arc::new(Mutex::new(ServiceSlots::new())),
+            services: Arc::new(Mutex::new(Slot::new(0))),
+        }
+    }
+
+    fn invalidate(&self, name: &str) {
+        self.invalidate_hosts(name);
+        lock(&self.services).invalidate();
+    }
+
+    // Heartbeats leave the list of services alone, so that it stays cached under steady load.
+    fn invalidate_hosts(&self, name: &str) {
+        if let Some(slot) = lock(&self.slots).slots.get_mut(name) {
+            slot.hosts.invalidate();
+        }
+    }
+
+    fn invalidate_weights(&self, name: &str) {
+        if let Some(slot) = lock(&self.slots).slots.get_mut(name) {
+            slot.weights.invalidate();
+        }
+    }
+
+    // Queries the underlying storage and stores the result into the slot of the service.
+    fn fetch(&self, name: &str, generation: u64) -> StorageFuture<Vec<Host>, S::E> {
+        let slots = self.slots.clone();
+        let serve_stale_on_error = self.serve_stale_on_error;
+        let name = name.to_owned();
+
+        let f = self.inner.query_items(&name);
+        Box::pin(async move {
+            let res = f.await;
+            let mut slots = lock(&slots);
+            let slot = &mut slots.get_or_insert(&name).hosts;
+            if slot.generation != generation {
+                return res;
+            }
+            slot.in_flight = None;
+            match res {
+                Ok(hosts) => {
+                    slot.entry = Some(CacheEntry {
+                        value: hosts.clone(),
+                        fetched_at: Instant::now(),
+                    });
+                    Ok(hosts)
+                }
+                Err(err) => match slot.entry {
+                    Some(ref e) if serve_stale_on_error => {
+                        warn!(
+                            "query_items(): serve stale hosts: service={}, age={:?}, error={}",
+                            name,
+                            e.fetched_at.elapsed(),
+                            err
+                        );
+                        Ok(filter_live_hosts(&e.value))
+                    }
+                    _ => Err(err),
+                },
+            }
+        })
+    }
+
+    // Queries the underlying storage and stores the result into the slot of the service.
+    fn fetch_weights(&self, name: &str, generation: u64) -> StorageFuture<RevisionWeights, S::E> {
+        let slots = self.slots.clone();
+        let serve_stale_on_error = self.serve_stale_on_error;
+        let name = name.to_owned();
+
+        let f = self.inner.query_revision_weights(&name);
+        Box::pin(async move {
+            let res = f.await;
+            let mut slots = lock(&slots);
+            let slot = &mut slots.get_or_insert(&name).weights;
+            if slot.generation != generation {
+                return res;
+            }
+            slot.in_flight = None;
+            match res {
+                Ok(weights) => {
+                    slot.entry = Some(CacheEntry {
+                        value: weights.clone(),
+                        fetched_at: Instant::now(),
+                    });
+                    Ok(weights)
+                }
+                Err(err) => match slot.entry {
+                    Some(ref e) if serve_stale_on_error => {
+                        warn!(
+                            "query_revision_weights(): serve stale weights: service={}, age={:?}, error={}",
+                            name,
+                            e.fetched_at.elapsed(),
+                            err
+                        );
+                        Ok(e.value.clone())
+                    }
+                    _ => Err(err),
+                },
+            }
+        })
+    }
+
+    // Lists services of the underlying storage and stores the result.
+    fn fetch_services(&self, generation: u64) -> StorageFuture<Vec<ServiceSummary>, S::E> {
+        let services = self.services.clone();
+        let serve_stale_on_error = self.serve_stale_on_error;
+
+        let f = self.inner.list_services();
+        Box::pin(async move {
+            let res = f.await;
+            let mut slot = lock(&services);
+            if slot.generation != generation {
+                return res;
+            }
+            slot.in_flight = None;
+            match res {
+                Ok(summaries) => {
+                    slot.entry = Some(CacheEntry {
+                        value: summaries.clone(),
+                        fetched_at: Instant::now(),
+                    });
+                    Ok(summaries)
+                }
+                Err(err) => match slot.entry {
+                    Some(ref e) if serve_stale_on_error => {
+                        warn!(
+                            "list_services(): serve stale services: age={:?}, error={}",
+                            e.fetched_at.elapsed(),
+                            err
+                        );
+                        Ok(e.value.clone())
+                    }
+                    _ => Err(err),
+                },
+            }
+        })
+    }
+}
+
+impl<S> Storage for CachedStorage<S>
+where
+    S: Storage,
+    S::E: Clone + Sync,
+{
+    type E = S::E;
+
+    fn query_items(&self, name: &str) -> StorageFuture<Vec<Host>, Self::E> {
+        let shared = {
+            let mut slots = lock(&self.slots);
+            slots.evict(self.ttl, self.serve_stale_on_error);
+            let slot = &mut slots.get_or_insert(name).hosts;
+
+            if let Some(ref e) = slot.entry {
+                if e.fetched_at.elapsed() < self.ttl {
+                    info!("query_items(): cache hit: service={}", name);
+                    return Box::pin(future::ok(filter_live_hosts(&e.value)));
+                }
+            }
+            match slot.in_flight {
+                Some(ref f) => f.clone(),
+                None => {
+                    let f = self.fetch(name, slot.generation).shared();
+                    slot.in_flight = Some(f.clone());
+                    f
+                }
+            }
+        };
+        Box::pin(shared)
+    }
+
+    fn store_item(&self, name: &str, host: Host) -> StorageFuture<(), Self::E> {
+        let st = self.clone();
+        let name = name.to_owned();
+        let f = self.inner.store_item(&name, host);
+        Box::pin(async move {
+            let res = f.await;
+            st.invalidate(&name);
+            res
+        })
+    }
+
+    fn delete_item(
+        &self,
+        name: &str,
+        ip: String,
+        port: u64,
+    ) -> StorageFuture<Option<Host>, Self::E> {
+        let st = self.clone();
+        let name = name.to_owned();
+        let f = self.inner.delete_item(&name, ip, port);
+        Box::pin(async move {
+            let res = f.await;
+            st.invalidate(&name);
+            res
+        })
+    }
+
+    fn refresh_item(
+        &self,
+        name: &str,
+        ip: String,
+        port: u64,
+        last_check_in: String,
+        expire_time: u64,
+    ) -> StorageFuture<Option<Host>, Self::E> {
+        let st = self.clone();
+        let name = name.to_owned();
+        let f = self
+            .inner
+            .refresh_item(&name, ip, port, last_check_in, expire_time);
+        Box::pin(async move {
+            let res = f.await;
+            st.invalidate_hosts(&name);
+            res
+        })
+    }
+
+    fn update_status(
+        &self,
+        name: &str,
+        ip: String,
+        port: u64,
+        status: HealthStatus,
+    ) -> StorageFuture<Option<Host>, Self::E> {
+        let st = self.clone();
+        let name = name.to_owned();
+        let f = self.inner.update_status(&name, ip, port, status);
+        Box::pin(async move {
+            let res = f.await;
+            st.invalidate(&name);
+            res
+        })
+    }
+
+    // Counts of expired hosts and the latest check-ins may be behind by up to `ttl`.
+    fn list_services(&self) -> StorageFuture<Vec<ServiceSummary>, Self::E> {
+        let shared = {
+            let mut slot = lock(&self.services);
+
+            if let Some(ref e) = slot.entry {
+                if e.fetched_at.elapsed() < self.ttl {
+                    info!("list_services(): cache hit");
+                    return Box::pin(future::ok(e.value.clone()));
+                }
+            }
+            match slot.in_flight {
+                Some(ref f) => f.clone(),
+                None => {
+                    let f = self.fetch_services(slot.generation).shared();
+                    slot.in_flight = Some(f.clone());
+                    f
+                }
+            }
+        };
+        Box::pin(shared)
+    }
+
+    // A rollout step through other sds instances takes effect after up to `ttl`, like their
+    // registrations.
+    fn query_revision_weights(&self, name: &str) -> StorageFuture<RevisionWeights, Self::E> {
+        let shared = {
+            let mut slots = lock(&self.slots);
+            slots.evict(self.ttl, self.serve_stale_on_error);
+            let slot = &mut slots.get_or_insert(name).weights;
+
+            if let Some(ref e) = slot.entry {
+                if e.fetched_at.elapsed() < self.ttl {
+                    info!("query_revision_weights(): cache hit: service={}", name);
+                    return Box::pin(future::ok(e.value.clone()));
+                }
+            }
+            match slot.in_flight {
+                Some(ref f) => f.clone(),
+                None => {
+                    let f = self.fetch_weights(name, slot.generation).shared();
+                    slot.in_flight = Some(f.clone());
+                    f
+                }
+            }
+        };
+        Box::pin(shared)
+    }
+
+    fn store_revision_weights(
+        &self,
+        name: &str,
+        weights: RevisionWeights,
+    ) -> StorageFuture<(), Self::E> {
+        let st = self.clone();
+        let name = name.to_owned();
+        let f = self.inner.store_revision_weights(&name, weights);
+        Box::pin(async move {
+            let res = f.await;
+            st.invalidate_weights(&name);
+            res
+        })
+    }
+
+    fn ttl(&self) -> u64 {
+        self.inner.ttl()
+    }
+}
+
+// A panic while holding a lock leaves nothing half-updated here, so recover from poisoning.
+fn lock<T>(m: &Mutex<T>) -> MutexGuard<'_, T> {
+    m.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+// Cached hosts may expire while they are cached.
+fn filter_live_hosts(hosts: &[Host]) -> Vec<Host> {
+    let epoch_now = match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(v) => v.as_secs(),
+        Err(_) => return hosts.to_vec(),
+    };
+    hosts
+        .iter()
+        .filter(|h| h.expire_time >= epoch_now)
+        .cloned()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+    use crate::memory::MemoryStorage;
+
+    #[tokio::test]
+    async fn evict_expired_slots() {
+        let s = CachedStorage::new(MemoryStorage::new(60), Duration::from_millis(20), false);
+        s.query_items("user").await.unwrap();
+        s.query_revision_weights("payment").await.unwrap();
+        assert_eq!(lock(&s.slots).slots.len(), 2);
+
+        thread::sleep(Duration::from_millis(30));
+        s.query_items("search").await.unwrap();
+        let slots = lock(&s.slots);
+        assert_eq!(slots.slots.keys().collect::<Vec<_>>(), ["search"]);
+        assert!(slots.next_generation > 0);
+    }
+
+    #[tokio::test]
+    async fn keep_stale_slots_with_values() {
+        let s = CachedStorage::new(MemoryStorage::new(60), Duration::from_millis(20), true);
+        s.query_items("user").await.unwrap();
+        s.store_revision_weights("payment", [("abc".to_owned(), 100)].into())
+            .await
+            .unwrap();
+        s.query_revision_weights("payment").await.unwrap();
+
+        thread::sleep(Duration::from_millis(30));
+        s.query_items("search").await.unwrap();
+        let slots = lock(&s.slots);
+        let mut names: Vec<&String> = slots.slots.keys().collect();
+        names.sort();
+        // Empty hosts are of no use when the storage fails.
+        assert_eq!(names, ["payment", "search"]);
+    }
+}
@@ -1,4 +1,15 @@
+pub mod ads;
+pub mod auth;
+pub mod cache;
+pub mod memory;
+pub mod metrics;
+pub mod quarantine;
 pub mod server;
+pub mod sqlite;
 pub mod storage;
+pub mod tls;
 pub mod types;
 pub mod v2xds;
+pub mod v3xds;
+pub mod watch;
+pub mod xdsproto;
@@ -1,12 +1,19 @@
+use aws_config::timeout::TimeoutConfig;
+use aws_config::BehaviorVersion;
 use log::error;
 use std::env;
+use std::net::{IpAddr, Ipv4Addr};
 use std::process::exit;
 use std::str;
 
+use sds::auth::Auth;
+use sds::cache::CachedStorage;
+use sds::memory::MemoryStorage;
+use sds::sqlite::SqliteStorage;
 use sds::storage::StorageImpl;
-use sds::types::Config;
+use sds::types::{AuthConfig, Config, FileConfig, Storage};
 
-// rusoto requires AWS_DEFAULT_REGION env.
+// The AWS SDK requires AWS_REGION or AWS_DEFAULT_REGION env when STORAGE_BACKEND is dynamodb.
 fn main() {
     env_logger::init();
 
@@ -19,17 +26,114 @@ fn main() {
         let v = fetch_env_var("HOST_TTL");
         parse_uint(&v)
     };
-    let table_name = fetch_env_var("DDB_TABLE");
-    let dynamodb_client = rusoto_dynamodb::DynamoDbClient::new(Default::default());
-
-    let storage = StorageImpl {
-        table_name,
-        ttl,
-        dynamodb_client,
-        timeout: get_timeout(),
+    let file_config = load_file_config();
+    let c = Config {
+        bind_addr: get_bind_addr(),
+        listen_port,
+        env: get_default_env(),
+        auth: load_auth(&file_config.auth),
+        cds: file_config.cds,
+        eds: file_config.eds,
+        tls: file_config.tls,
     };
-    let c = Config { listen_port };
-    sds::server::run(&c, storage);
+
+    let runtime = match build_runtime() {
+        Ok(v) => v,
+        Err(e) => {
+            error!("Failed to start tokio runtime: {}", e);
+            exit(1);
+        }
+    };
+    runtime.block_on(start(c, ttl));
+}
+
+async fn start(c: Config, ttl: u64) {
+    match get_storage_backend().as_str() {
+        "dynamodb" => {
+            let table_name = fetch_env_var("DDB_TABLE");
+            let sdk_config = aws_config::defaults(BehaviorVersion::latest())
+                .timeout_config(
+                    TimeoutConfig::builder()
+                        .operation_timeout(get_timeout())
+                        .build(),
+                )
+                .load()
+                .await;
+            let dynamodb_client = aws_sdk_dynamodb::Client::new(&sdk_config);
+
+            let storage = StorageImpl {
+                table_name,
+                ttl,
+                dynamodb_client,
+                skip_corrupt_items: get_skip_corrupt_items(),
+            };
+            serve(&c, storage).await;
+        }
+        "sqlite" => {
+            let path = fetch_env_var("SQLITE_PATH");
+            let storage = match SqliteStorage::open(&path, ttl, get_skip_corrupt_items()) {
+                Ok(v) => v,
+                Err(e) => {
+                    error!("Failed to open SQLite database: path={}, error={}", path, e);
+                    exit(1);
+                }
+            };
+            serve(&c, storage).await;
+        }
+        "memory" => {
+            log::warn!("Use in-memory storage: registrations are lost on restart");
+            serve(&c, MemoryStorage::new(ttl)).await;
+        }
+        v => {
+            error!("STORAGE_BACKEND env is invalid: value={}", v);
+            exit(1);
+        }
+    }
+}
+
+async fn serve<S>(c: &Config, s: S)
+where
+    S: Storage,
+    S::E: Clone + Sync,
+{
+    let res = match get_cache_ttl() {
+        Some(ttl) => {
+            let serve_stale_on_error = get_cache_serve_stale();
+            log::info!(
+                "Enable cache: ttl={:?}, serve_stale_on_error={}",
+                ttl,
+                serve_stale_on_error
+            );
+            sds::server::run(c, CachedStorage::new(s, ttl, serve_stale_on_error)).await
+        }
+        None => sds::server::run(c, s).await,
+    };
+    if let Err(e) = res {
+        error!("server error: {}", e);
+        exit(1);
+    }
+}
+
+fn build_runtime() -> std::io::Result<tokio::runtime::Runtime> {
+    let mut builder = tokio::runtime::Builder::new_multi_thread();
+    builder.enable_all();
+    if let Some(num) = get_core_threads() {
+        log::info!("Set worker_threads to {}", num);
+        builder.worker_threads(num);
+    }
+    builder.build()
+}
+
+fn get_core_threads() -> Option<usize> {
+    std::env::var("CORE_THREADS")
+        .ok()
+        .and_then(|core_threads| match core_threads.parse() {
+            Ok(num) => Some(num),
+            Err(e) => {
+                log::warn!("unable to parse CORE_THREADS into usize: {}", e);
+                None
+            }
+        })
 }
 
 fn fetch_env_var(k: &'static str) -> String {
@@ -55,6 +159,40 @@ where
     }
 }
 
+fn get_bind_addr() -> IpAddr {
+    const DEFAULT_BIND_ADDR: IpAddr = IpAddr::V4(Ipv4Addr::UNSPECIFIED);
+
+    match env::var("BIND_ADDR") {
+        Ok(v) => match v.parse() {
+            Ok(addr) => addr,
+            Err(_) => {
+                error!("BIND_ADDR env is invalid: value={}", v);
+                exit(1);
+            }
+        },
+        Err(_) => DEFAULT_BIND_ADDR,
+    }
+}
+
+fn get_default_env() -> String {
+    const DEFAULT_ENV: &str = "production";
+
+    match env::var("DEFAULT_ENV") {
+        Ok(v) => {
+            let is_valid = !v.is_empty()
+                && v.len() <= 64
+                && v.chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+            if !is_valid {
+                error!("DEFAULT_ENV env is invalid: value={}", v);
+                exit(1);
+            }
+            v
+        }
+        Err(_) => DEFAULT_ENV.to_owned(),
+    }
+}
+
 fn get_timeout() -> std::time::Duration {
     const DEFAULT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
 
@@ -72,3 +210,80 @@ fn get_timeout() -> std::time::Duration {
         })
         .unwrap_or(DEFAULT_TIMEOUT)
 }
+
+fn load_file_config() -> FileConfig {
+    let path = match env::var("CONFIG_FILE") {
+        Ok(v) => v,
+        Err(_) => return Default::default(),
+    };
+    let content = match std::fs::read_to_string(&path) {
+        Ok(v) => v,
+        Err(e) => {
+            error!("Failed to read config file: path={}, error={}", path, e);
+            exit(1);
+        }
+    };
+    match serde_json::from_str::<FileConfig>(&content) {
+        Ok(v) => {
+            for (name, service) in v.eds.services.iter() {
+                if service.canary_percentage.is_some_and(|p| p > 100) {
+                    error!(
+                        "canary_percentage must be at most 100: path={}, service={}",
+                        path, name
+                    );
+                    exit(1);
+                }
+            }
+            log::info!("Load config file: path={}", path);
+            v
+        }
+        Err(e) => {
+            error!("Failed to parse config file: path={}, error={}", path, e);
+            exit(1);
+        }
+    }
+}
+
+fn load_auth(c: &AuthConfig) -> Option<Auth> {
+    let path = c.tokens_file.as_ref()?;
+    match Auth::load(path, c.protect_reads) {
+        Ok(v) => {
+            log::info!("Load tokens file: path={}", path);
+            Some(v)
+        }
+        Err(e) => {
+            error!("Failed to load tokens file: path={}, error={}", path, e);
+            exit(1);
+        }
+    }
+}
+
+fn get_storage_backend() -> String {
+    const DEFAULT_BACKEND: &str = "dynamodb";
+
+    std::env::var("STORAGE_BACKEND").unwrap_or_else(|_| DEFAULT_BACKEND.to_owned())
+}
+
+fn get_cache_ttl() -> Option<std::time::Duration> {
+    std::env::var("CACHE_TTL_MS")
+        .ok()
+        .and_then(|ttl_str| match ttl_str.parse() {
+            Ok(ttl_ms) => Some(std::time::Duration::from_millis(ttl_ms)),
+            Err(e) => {
+                log::warn!("unable to parse CACHE_TTL_MS into integer: {}", e);
+                None
+            }
+        })
+}
+
+fn get_cache_serve_stale() -> bool {
+    std::env::var("CACHE_SERVE_STALE")
+        .map(|v| v == "true")
+        .unwrap_or(false)
+}
+
+fn get_skip_corrupt_items() -> bool {
+    std::env::var("SKIP_CORRUPT_ITEMS")
+        .map(|v| v == "true")
+        .unwrap_or(false)
+}
@@ -0,0 +1,289 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use futures::future;
+use log::info;
+
+use super::metrics;
+use super::storage::{fetch_epoch_now, join_ip_port, ErrorKind, StorageError};
+use super::types::{HealthStatus, Host, RevisionWeights, ServiceSummary, Storage, StorageFuture};
+
+// Keeps hosts in process memory. Entries are lost on restart, so this is meant for local
+// development and tests rather than production use.
+#[derive(Clone)]
+pub struct MemoryStorage {
+    pub ttl: u64,
+    hosts: Arc<RwLock<HashMap<String, HashMap<String, Host>>>>,
+    revision_weights: Arc<RwLock<HashMap<String, RevisionWeights>>>,
+}
+
+impl MemoryStorage {
+    pub fn new(ttl: u64) -> MemoryStorage {
+        MemoryStorage {
+            ttl,
+            hosts: Arc::new(RwLock::new(HashMap::new())),
+            revision_weights: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    fn query_hosts(&self, name: &str) -> Result<Vec<Host>, StorageError> {
+        let epoch_now = fetch_epoch_now()?;
+        let mut services = self.hosts.write().map_err(|_| build_lock_error())?;
+
+        let mut hosts = Vec::new();
+        let mut expired_hosts_size = 0;
+        if let Some(entries) = services.get_mut(name) {
+            // Drop expired entries the same way DynamoDB's TTL eventually does.
+            entries.retain(|_, host| {
+                if host.expire_time >= epoch_now {
+                    true
+                } else {
+                    info!(
+                        "Expired host found: service={}, ip={}, port={}, expire_time={}, now={}",
+                        name, host.ip_address, host.port, host.expire_time, epoch_now
+                    );
+                    expired_hosts_size += 1;
+                    false
+                }
+            });
+            hosts.extend(entries.values().cloned());
+        }
+        metrics::set_hosts(name, hosts.len(), expired_hosts_size);
+        info!(
+            "query_items(): succeed to return hosts: service={}, hosts-size={}",
+            name,
+            hosts.len()
+        );
+        Ok(hosts)
+    }
+
+    fn store_host(&self, name: &str, mut host: Host) -> Result<(), StorageError> {
+        let ip_port = join_ip_port(&host.ip_address, u64::from(host.port));
+        let epoch_now = fetch_epoch_now()?;
+        let mut services = self.hosts.write().map_err(|_| build_lock_error())?;
+        info!(
+            "store_item(): succeed to store item: service={}, ip={}, port={}",
+            name, host.ip_address, host.port
+        );
+        let entries = services.entry(name.to_owned()).or_insert_with(HashMap::new);
+        if let Some(old) = entries.get(&ip_port).filter(|h| h.expire_time >= epoch_now) {
+            host.health_status = old.health_status;
+        }
+        entries.insert(ip_port, host);
+        Ok(())
+    }
+
+    fn delete_host(&self, name: &str, ip: String, port: u64) -> Result<Option<Host>, StorageError> {
+        let ip_port = join_ip_port(&ip, port);
+        let epoch_now = fetch_epoch_now()?;
+        let mut services = self.hosts.write().map_err(|_| build_lock_error())?;
+
+        let removed = services
+            .get_mut(name)
+            .and_then(|entries| entries.remove(&ip_port));
+        info!(
+            "delete_item(): succeed to delete_item item: service={}, ip={}, port={}",
+            name, ip, port
+        );
+        match removed {
+            Some(h) => {
+                if h.expire_time >= epoch_now {
+                    Ok(Some(h))
+                } else {
+                    Ok(None)
+                }
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn refresh_host(
+        &self,
+        name: &str,
+        ip: String,
+        port: u64,
+        last_check_in: String,
+        expire_time: u64,
+    ) -> Result<Option<Host>, StorageError> {
+        let ip_port = join_ip_port(&ip, port);
+        let epoch_now = fetch_epoch_now()?;
+        let mut services = self.hosts.write().map_err(|_| build_lock_error())?;
+
+        let host = services
+            .get_mut(name)
+            .and_then(|entries| entries.get_mut(&ip_port))
+            .filter(|h| h.expire_time >= epoch_now);
+        match host {
+            Some(h) => {
+                h.last_check_in = last_check_in;
+                h.expire_time = expire_time;
+                info!(
+                    "refresh_item(): succeed to refresh item: service={}, ip={}, port={}",
+                    name, ip, port
+                );
+                Ok(Some(h.clone()))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn update_host_status(
+        &self,
+        name: &str,
+        ip: String,
+        port: u64,
+        status: HealthStatus,
+    ) -> Result<Option<Host>, StorageError> {
+        let ip_port = join_ip_port(&ip, port);
+        let epoch_now = fetch_epoch_now()?;
+        let mut services = self.hosts.write().map_err(|_| build_lock_error())?;
+
+        let host = services
+            .get_mut(name)
+            .and_then(|entries| entries.get_mut(&ip_port))
+            .filter(|h| h.expire_time >= epoch_now);
+        match host {
+            Some(h) => {
+                h.health_status = status;
+                info!(
+                    "update_status(): succeed to update status: service={}, ip={}, port={}, status={}",
+                    name,
+                    ip,
+                    port,
+                    status.as_str()
+                );
+                Ok(Some(h.clone()))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn summarize_services(&self) -> Result<Vec<ServiceSummary>, StorageError> {
+        let epoch_now = fetch_epoch_now()?;
+        let services = self.hosts.read().map_err(|_| build_lock_error())?;
+
+        // Ordered by the key as the other backends are.
+        let mut names: Vec<&String> = services
+            .iter()
+            .filter(|(_, entries)| !entries.is_empty())
+            .map(|(name, _)| name)
+            .collect();
+        names.sort();
+
+        let mut summaries = Vec::new();
+        for name in names {
+            let mut summary = ServiceSummary::new(name.to_owned());
+            for h in services[name].values() {
+                summary.add_host(h.expire_time, &h.last_check_in, &h.revision, epoch_now);
+            }
+            summaries.push(summary);
+        }
+        info!(
+            "list_services(): succeed to return services: services-size={}",
+            summaries.len()
+        );
+        Ok(summaries)
+    }
+
+    fn query_weights(&self, name: &str) -> Result<RevisionWeights, StorageError> {
+        let revision_weights = self
+            .revision_weights
+            .read()
+            .map_err(|_| build_lock_error())?;
+        Ok(revision_weights.get(name).cloned().unwrap_or_default())
+    }
+
+    fn store_weights(&self, name: &str, weights: RevisionWeights) -> Result<(), StorageError> {
+        let mut revision_weights = self
+            .revision_weights
+            .write()
+            .map_err(|_| build_lock_error())?;
+        info!(
+            "store_revision_weights(): succeed to store weights: service={}, weights={:?}",
+            name, weights
+        );
+        if weights.is_empty() {
+            revision_weights.remove(name);
+        } else {
+            revision_weights.insert(name.to_owned(), weights);
+        }
+        Ok(())
+    }
+}
+
+impl Storage for MemoryStorage {
+    type E = StorageError;
+
+    fn query_items(&self, name: &str) -> StorageFuture<Vec<Host>, Self::E> {
+        Box::pin(future::ready(self.query_hosts(name)))
+    }
+
+    fn store_item(&self, name: &str, host: Host) -> StorageFuture<(), Self::E> {
+        Box::pin(future::ready(self.store_host(name, host)))
+    }
+
+    fn delete_item(
+        &self,
+        name: &str,
+        ip: String,
+        port: u64,
+    ) -> StorageFuture<Option<Host>, Self::E> {
+        Box::pin(future::ready(self.delete_host(name, ip, port)))
+    }
+
+    fn refresh_item(
+        &self,
+        name: &str,
+        ip: String,
+        port: u64,
+        last_check_in: String,
+        expire_time: u64,
+    ) -> StorageFuture<Option<Host>, Self::E> {
+        Box::pin(future::ready(self.refresh_host(
+            name,
+            ip,
+            port,
+            last_check_in,
+            expire_time,
+        )))
+    }
+
+    fn update_status(
+        &self,
+        name: &str,
+        ip: String,
+        port: u64,
+        status: HealthStatus,
+    ) -> StorageFuture<Option<Host>, Self::E> {
+        Box::pin(future::ready(
+            self.update_host_status(name, ip, port, status),
+        ))
+    }
+
+    fn list_services(&self) -> StorageFuture<Vec<ServiceSummary>, Self::E> {
+        Box::pin(future::ready(self.summarize_services()))
+    }
+
+    fn query_revision_weights(&self, name: &str) -> StorageFuture<RevisionWeights, Self::E> {
+        Box::pin(future::ready(self.query_weights(name)))
+    }
+
+    fn store_revision_weights(
+        &self,
+        name: &str,
+        weights: RevisionWeights,
+    ) -> StorageFuture<(), Self::E> {
+        Box::pin(future::ready(self.store_weights(name, weights)))
+    }
+
+    fn ttl(&self) -> u64 {
+        self.ttl
+    }
+}
+
+fn build_lock_error() -> StorageError {
+    StorageError::new(
+        ErrorKind::System,
+        "In-memory storage lock is poisoned".to_owned(),
+    )
+}
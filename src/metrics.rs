@@ -0,0 +1,150 @@
+use std::time::Duration;
+
+use hyper::StatusCode;
+use lazy_static::lazy_static;
+use prometheus::core::Collector;
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGaugeVec, Opts, TextEncoder,
+};
+
+lazy_static! {
+    static ref HTTP_REQUESTS_TOTAL: IntCounterVec = register(
+        IntCounterVec::new(
+            Opts::new("sds_http_requests_total", "Number of HTTP requests."),
+            &["method", "route", "status"],
+        )
+        .unwrap()
+    );
+    static ref HTTP_REQUEST_DURATION_SECONDS: HistogramVec = register(
+        HistogramVec::new(
+            HistogramOpts::new(
+                "sds_http_request_duration_seconds",
+                "HTTP request latencies in seconds."
+            ),
+            &["method", "route", "status"],
+        )
+        .unwrap()
+    );
+    static ref DYNAMODB_REQUEST_DURATION_SECONDS: HistogramVec = register(
+        HistogramVec::new(
+            HistogramOpts::new(
+                "sds_dynamodb_request_duration_seconds",
+                "DynamoDB API call latencies in seconds."
+            ),
+            &["operation"],
+        )
+        .unwrap()
+    );
+    static ref STORAGE_ERRORS_TOTAL: IntCounterVec = register(
+        IntCounterVec::new(
+            Opts::new("sds_storage_errors_total", "Number of storage errors."),
+            &["kind"],
+        )
+        .unwrap()
+    );
+    static ref HOSTS: IntGaugeVec = register(
+        IntGaugeVec::new(
+            Opts::new(
+                "sds_hosts",
+                "Number of hosts of each service observed by the last query."
+            ),
+            &["service", "state"],
+        )
+        .unwrap()
+    );
+    static ref QUARANTINED_ITEMS: IntGaugeVec = register(
+        IntGaugeVec::new(
+            Opts::new(
+                "sds_quarantined_items",
+                "Number of corrupt items of each service skipped by the last query."
+            ),
+            &["service"],
+        )
+        .unwrap()
+    );
+}
+
+fn register<C: Collector + Clone + 'static>(c: C) -> C {
+    prometheus::register(Box::new(c.clone())).expect("failed to register a metric");
+    c
+}
+
+pub fn observe_http_request(method: &str, route: &str, status: StatusCode, elapsed: Duration) {
+    let labels = [method, route, status.as_str()];
+    HTTP_REQUESTS_TOTAL.with_label_values(&labels).inc();
+    HTTP_REQUEST_DURATION_SECONDS
+        .with_label_values(&labels)
+        .observe(duration_to_seconds(elapsed));
+}
+
+pub fn observe_dynamodb_request(operation: &str, elapsed: Duration) {
+    DYNAMODB_REQUEST_DURATION_SECONDS
+        .with_label_values(&[operation])
+        .observe(duration_to_seconds(elapsed));
+}
+
+pub fn inc_storage_errors(kind: &str) {
+    STORAGE_ERRORS_TOTAL.with_label_values(&[kind]).inc();
+}
+
+// Services without any host drop their series, so that queries of arbitrary names do not grow
+// the label set forever.
+pub fn set_hosts(service: &str, live: usize, expired: usize) {
+    if live == 0 && expired == 0 {
+        // Fails only when the series does not exist.
+        let _ = HOSTS.remove_label_values(&[service, "live"]);
+        let _ = HOSTS.remove_label_values(&[service, "expired"]);
+        return;
+    }
+    HOSTS.with_label_values(&[service, "live"]).set(live as i64);
+    HOSTS
+        .with_label_values(&[service, "expired"])
+        .set(expired as i64);
+}
+
+pub fn set_quarantined_items(service: &str, size: usize) {
+    QUARANTINED_ITEMS
+        .with_label_values(&[service])
+        .set(size as i64);
+}
+
+// Returns the metrics in Prometheus' text format with its content type.
+pub fn encode() -> Result<(String, Vec<u8>), prometheus::Error> {
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    encoder.encode(&prometheus::gather(), &mut buffer)?;
+    Ok((encoder.format_type().to_owned(), buffer))
+}
+
+fn duration_to_seconds(d: Duration) -> f64 {
+    d.as_secs() as f64 + f64::from(d.subsec_nanos()) / 1_000_000_000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use prometheus::core::Collector;
+
+    use super::*;
+
+    fn has_hosts_series(service: &str) -> bool {
+        HOSTS
+            .collect()
+            .iter()
+            .flat_map(|mf| mf.get_metric())
+            .any(|m| {
+                m.get_label()
+                    .iter()
+                    .any(|l| l.get_name() == "service" && l.get_value() == service)
+            })
+    }
+
+    #[test]
+    fn remove_hosts_of_empty_services() {
+        set_hosts("metrics-test", 1, 2);
+        assert!(has_hosts_series("metrics-test"));
+        set_hosts("metrics-test", 0, 0);
+        assert!(!has_hosts_series("metrics-test"));
+        set_hosts("metrics-test", 0, 0);
+        assert!(!has_hosts_series("metrics-test"));
+    }
+}
@@ -0,0 +1,57 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+use serde_derive::Serialize;
+
+use super::metrics;
+use super::storage::StorageError;
+
+lazy_static! {
+    static ref ITEMS: Mutex<BTreeMap<String, Vec<QuarantinedItem>>> = Mutex::new(BTreeMap::new());
+}
+
+// A stored item skipped by a query because it could not be converted into a host.
+#[derive(Serialize, Debug, Clone)]
+pub struct QuarantinedItem {
+    pub service: String,
+    // Missing when the sort key itself is not a string.
+    pub ip_port: Option<String>,
+    // The offending attribute.
+    pub key: Option<String>,
+    pub reason: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Quarantine {
+    pub items: Vec<QuarantinedItem>,
+}
+
+impl QuarantinedItem {
+    pub(crate) fn new(service: &str, ip_port: Option<String>, e: &StorageError) -> QuarantinedItem {
+        QuarantinedItem {
+            service: service.to_owned(),
+            ip_port,
+            key: e.key.to_owned(),
+            reason: e.msg.to_owned(),
+        }
+    }
+}
+
+// Replaces the quarantined items of the service with the ones found by the last query, so that
+// fixed or deleted items leave the quarantine.
+pub fn set(service: &str, items: Vec<QuarantinedItem>) {
+    metrics::set_quarantined_items(service, items.len());
+    let mut m = ITEMS.lock().unwrap_or_else(|e| e.into_inner());
+    if items.is_empty() {
+        m.remove(service);
+    } else {
+        m.insert(service.to_owned(), items);
+    }
+}
+
+// Returns every quarantined item, in ascending order of the service name.
+pub fn list() -> Vec<QuarantinedItem> {
+    let m = ITEMS.lock().unwrap_or_else(|e| e.into_inner());
+    m.values().flat_map(|items| items.iter().cloned()).collect()
+}
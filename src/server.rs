@@ -1,122 +1,497 @@
+use std::convert::Infallible;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
 use std::str;
+use std::sync::Arc;
 use std::time;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
+use bytes::Bytes;
 use chrono;
-use futures::{future, Future, Stream};
-use hyper;
+use futures::future;
+use http_body_util::{BodyExt, Full};
+use hyper::body::Incoming;
+use hyper::header::{HeaderValue, CONTENT_TYPE, RETRY_AFTER, WWW_AUTHENTICATE};
 use hyper::service::service_fn;
-use hyper::Server;
-use hyper::{Body, Method, Request, Response, StatusCode};
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto;
 use lazy_static::lazy_static;
-use log::{debug, error, info};
-use regex::Regex;
+use log::{debug, error, info, warn};
+use regex::{Captures, Regex};
+use serde::de::DeserializeOwned;
 use serde_derive::{Deserialize, Serialize};
 use serde_json;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+use tonic::body::BoxBody;
+use tonic::Status;
 use uuid::Uuid;
 
-use super::types::{Config, Host, Registration, Storage, Tag};
+use super::ads;
+use super::auth::Principal;
+use super::metrics;
+use super::quarantine::{self, Quarantine};
+use super::storage::{
+    build_service_key, split_service_key, trim_brackets, ErrorKind, StorageError,
+};
+use super::tls::{self, ClientCert};
+use super::types::{
+    Config, HealthStatus, Host, Registration, RevisionWeights, ServiceSummary, Services, Storage,
+    Tag,
+};
+use super::v2xds;
 use super::v2xds::{
-    hosts_to_locality_lb_endpoints, ClusterLoadAssignment, DiscoveryRequest, EdsDiscoveryResponse,
-    EDS_TYPE_URL,
+    apply_group_shares, build_eds_cluster, build_version_info, hosts_to_locality_lb_endpoints,
+    CdsDiscoveryResponse, Cluster, ClusterLoadAssignment, DiscoveryRequest, EdsDiscoveryResponse,
+    GroupShares, Locality,
 };
+use super::v3xds;
+use super::watch::{self, WatchedStorage};
 
-type BoxFut = Box<dyn Future<Item = Response<Body>, Error = hyper::Error> + Send>;
+type Body = Full<Bytes>;
+
+// Seconds clients should wait before retrying a request failed by a transient storage error.
+const RETRY_AFTER_SECS: &str = "1";
 
 #[derive(Serialize, Deserialize, Debug)]
 struct RegistrationParam {
+    // Overrides the environment given by the query string.
+    #[serde(default)]
+    env: Option<String>,
     ip: String,
     port: u16,
     revision: String,
     tags: Tag,
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+struct StatusParam {
+    status: HealthStatus,
+}
+
+// The namespace given by the "/ns/:namespace" prefix of the path, put into request extensions.
+#[derive(Clone, Debug)]
+struct Namespace(String);
+
+// Where a request reads and writes hosts.
+struct Scope {
+    namespace: Option<String>,
+    env: String,
+}
+
+impl Scope {
+    fn key(&self, name: &str) -> String {
+        build_service_key(self.namespace.as_deref(), name, &self.env)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct RevisionWeightsParam {
+    weights: RevisionWeights,
+}
+
 #[derive(Serialize, Debug)]
 struct ErrorResponse {
     // Machine readable error code.
     id: ErrorId,
     // Error description for human.
     reason: String,
+    // The offending attribute of a corrupt record.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    key: Option<String>,
+    // Every invalid field of a request body.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    fields: Vec<FieldError>,
+}
+
+#[derive(Serialize, Debug)]
+struct FieldError {
+    // The path of the field like "tags.az".
+    field: String,
+    reason: String,
 }
 
 #[derive(Serialize, Debug)]
 enum ErrorId {
     HostNotFound,
+    RouteNotFound,
+    InvalidRequest,
+    InvalidJson,
+    InvalidPort,
+    InvalidRegistration,
+    InvalidRevisionWeights,
+    Unauthorized,
+    Forbidden,
+    Throttled,
+    Timeout,
+    CorruptRecord,
+    InternalError,
 }
 
-pub fn run<S: Storage>(c: &Config, s: S) {
-    // XXX: ipv4 only
-    let addr = ([0, 0, 0, 0], c.listen_port).into();
-    let new_service = move || {
-        let st = s.clone();
-        service_fn(move |req| {
-            let stt = st.clone();
-            route(stt, req)
-        })
+impl ErrorResponse {
+    fn new(id: ErrorId, reason: String) -> ErrorResponse {
+        ErrorResponse {
+            id,
+            reason,
+            key: None,
+            fields: Vec::new(),
+        }
+    }
+}
+
+impl FieldError {
+    fn new(field: &str, reason: &str) -> FieldError {
+        FieldError {
+            field: field.to_owned(),
+            reason: reason.to_owned(),
+        }
+    }
+}
+
+// Serves HTTP/1 and HTTP/2 connections, over TLS when configured, and gRPC streams on HTTP/2 ones.
+// Returns only when the listener cannot be bound or the TLS settings are invalid.
+pub async fn run<S: Storage>(c: &Config, s: S) -> io::Result<()> {
+    let poll_interval = c
+        .eds
+        .stream_poll_interval_ms
+        .unwrap_or(watch::DEFAULT_POLL_INTERVAL_MS);
+    let s = WatchedStorage::new(s, time::Duration::from_millis(poll_interval));
+    let acceptor = match c.tls {
+        Some(ref t) => Some(tls::build_acceptor(t)?),
+        None => None,
     };
-    let server = Server::bind(&addr)
-        .serve(new_service)
-        .map_err(|e| error!("server error: {}", e));
-    info!("Listening on {}", addr);
-    let mut builder = tokio::runtime::Builder::new();
-    if let Some(num) = get_core_threads() {
-        log::info!("Set core_threads to {}", num);
-        builder.core_threads(num);
-    }
-    let mut entered = tokio_executor::enter().expect("nested tokio::run");
-    let mut runtime = builder.build().expect("failed to start new Runtime");
-    runtime.spawn(server);
-    entered
-        .block_on(runtime.shutdown_on_idle())
-        .expect("shutdown cannot error");
-}
-
-fn get_core_threads() -> Option<usize> {
-    std::env::var("CORE_THREADS")
-        .ok()
-        .and_then(|core_threads| match core_threads.parse() {
-            Ok(num) => Some(num),
+    let addr = SocketAddr::from((c.bind_addr, c.listen_port));
+    let listener = TcpListener::bind(addr).await?;
+    info!(
+        "Listening on {}{}",
+        addr,
+        if acceptor.is_some() { " with TLS" } else { "" }
+    );
+
+    let config = Arc::new(c.clone());
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
             Err(e) => {
-                log::warn!("unable to parse CORE_THREADS into usize: {}", e);
-                None
+                // e.g. too many open files. Back off instead of spinning on the error.
+                error!("accept error: {}", e);
+                tokio::time::sleep(time::Duration::from_secs(1)).await;
+                continue;
             }
-        })
+        };
+        let st = s.clone();
+        let conf = config.clone();
+        let acceptor = acceptor.clone();
+        tokio::spawn(async move {
+            match acceptor {
+                Some(acceptor) => {
+                    let stream = match acceptor.accept(stream).await {
+                        Ok(v) => v,
+                        Err(e) => {
+                            warn!("TLS handshake error: {}", e);
+                            return;
+                        }
+                    };
+                    let client_cert = tls::client_cert(stream.get_ref().1);
+                    serve_connection(stream, st, conf, client_cert).await
+                }
+                None => serve_connection(stream, st, conf, None).await,
+            }
+        });
+    }
 }
 
-fn route<S: Storage>(s: S, req: Request<Body>) -> BoxFut {
+async fn serve_connection<S, IO>(
+    io: IO,
+    s: WatchedStorage<S>,
+    c: Arc<Config>,
+    client_cert: Option<ClientCert>,
+) where
+    S: Storage,
+    IO: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let service = service_fn(move |mut req: Request<Incoming>| {
+        if let Some(ref cert) = client_cert {
+            req.extensions_mut().insert(cert.clone());
+        }
+        let s = s.clone();
+        let c = c.clone();
+        async move {
+            if ads::is_grpc(&req) {
+                return Ok::<_, Infallible>(serve_grpc(s, c, req).await);
+            }
+            route(s, c, req)
+                .await
+                .map(|res| res.map(tonic::body::boxed))
+        }
+    });
+    let builder = auto::Builder::new(TokioExecutor::new());
+    if let Err(e) = builder.serve_connection(TokioIo::new(io), service).await {
+        error!("server error: {}", e);
+    }
+}
+
+// Streams are authenticated like other reads, but by the headers alone since the body is never
+// complete. So signatures must cover an empty body, and bearer tokens are simpler to use here.
+async fn serve_grpc<S: Storage>(
+    s: WatchedStorage<S>,
+    c: Arc<Config>,
+    req: Request<Incoming>,
+) -> Response<BoxBody> {
+    let mut head = Request::new(Bytes::new());
+    *head.method_mut() = req.method().clone();
+    *head.uri_mut() = req.uri().clone();
+    *head.headers_mut() = req.headers().clone();
+    if let Some(cert) = req.extensions().get::<ClientCert>() {
+        head.extensions_mut().insert(cert.clone());
+    }
+    if authenticate(&c, &mut head).is_err() || authorize_read(&c, &head).is_err() {
+        return Status::unauthenticated("Valid credentials are required").into_http();
+    }
+    ads::serve(s, c, req).await
+}
+
+async fn route<S: Storage>(
+    s: S,
+    c: Arc<Config>,
+    req: Request<Incoming>,
+) -> Result<Response<Body>, Infallible> {
     info!(
         "Recieve request: method={}, path={}",
         req.method(),
         req.uri().to_owned().path()
     );
+    let started = Instant::now();
+    let method = req.method().to_string();
+    let route_name = route_name(req.uri().path());
+    let res = dispatch(&s, &c, req).await;
+    metrics::observe_http_request(&method, route_name, res.status(), started.elapsed());
+    Ok(res)
+}
+
+// Reads the whole body up front, since HMAC signatures cover it.
+async fn dispatch<S: Storage>(s: &S, c: &Config, req: Request<Incoming>) -> Response<Body> {
+    let (parts, body) = req.into_parts();
+    let body = match body.collect().await {
+        Ok(v) => v.to_bytes(),
+        Err(e) => {
+            return build_400(
+                ErrorId::InvalidRequest,
+                format!("Failed to read request body: {}", e),
+            )
+        }
+    };
+    handle(s, c, Request::from_parts(parts, body)).await
+}
+
+async fn handle<S: Storage>(s: &S, c: &Config, mut req: Request<Bytes>) -> Response<Body> {
+    if let Err(res) = authenticate(c, &mut req) {
+        return *res;
+    }
+    if let Err(res) = strip_namespace(&mut req) {
+        return *res;
+    }
+    if let Err(res) = authorize_read(c, &req) {
+        return *res;
+    }
     match *req.method() {
-        Method::GET => route_get_req(&s, req),
-        Method::POST => route_post_req(s, req),
-        Method::DELETE => route_delete_req(&s, req),
-        _ => res_404(),
+        Method::GET => route_get_req(s, c, req).await,
+        Method::POST => route_post_req(s, c, req).await,
+        Method::PUT => route_put_req(s, c, req).await,
+        Method::DELETE => route_delete_req(s, c, req).await,
+        _ => build_404(),
+    }
+}
+
+// Puts the client authenticated by the Authorization header into request extensions. Invalid
+// credentials are rejected on every route.
+fn authenticate(c: &Config, req: &mut Request<Bytes>) -> Result<(), Box<Response<Body>>> {
+    let auth = match c.auth {
+        Some(ref v) => v,
+        None => return Ok(()),
+    };
+    match auth.authenticate(req) {
+        Ok(Some(principal)) => {
+            info!("Authenticated: id={}", principal.id);
+            req.extensions_mut().insert(principal);
+            Ok(())
+        }
+        Ok(None) => Ok(()),
+        Err(reason) => Err(Box::new(build_401(reason))),
+    }
+}
+
+// Only health checks and metrics stay open when reads are protected. A verified client
+// certificate is as good as a token for reading.
+fn authorize_read(c: &Config, req: &Request<Bytes>) -> Result<(), Box<Response<Body>>> {
+    let protect_reads = c.auth.as_ref().is_some_and(|a| a.protect_reads);
+    let is_open = matches!(req.uri().path(), "/" | "/hc" | "/metrics");
+    let authenticated = req.extensions().get::<Principal>().is_some()
+        || req.extensions().get::<ClientCert>().is_some();
+    if !protect_reads || is_open || authenticated {
+        return Ok(());
+    }
+    Err(Box::new(build_401("Token is required".to_owned())))
+}
+
+// Called by every route writing a service. Tokens may only write the services matching their
+// patterns, and client certificates the services named by their URI SANs when authorize_by_san
+// is set. Either one is enough.
+fn authorize_write(
+    c: &Config,
+    req: &Request<Bytes>,
+    name: &str,
+) -> Result<(), Box<Response<Body>>> {
+    let authorize_by_san = c.tls.as_ref().is_some_and(|t| t.authorize_by_san);
+    if c.auth.is_none() && !authorize_by_san {
+        return Ok(());
+    }
+    let namespace = request_namespace(req);
+    let qualified_name = match namespace {
+        Some(ref ns) => format!("{}/{}", ns, name),
+        None => name.to_owned(),
+    };
+    let principal = req.extensions().get::<Principal>();
+    let client_cert = req
+        .extensions()
+        .get::<ClientCert>()
+        .filter(|_| authorize_by_san);
+    if principal.is_some_and(|p| p.can_write(&qualified_name))
+        || client_cert.is_some_and(|cert| cert.can_write(name, namespace.as_deref()))
+    {
+        return Ok(());
+    }
+
+    let message = match (principal, client_cert) {
+        (Some(p), _) => format!("Token {} may not write service {}", p.id, qualified_name),
+        (None, Some(cert)) => format!(
+            "Client certificate with URIs {:?} may not write service {}",
+            cert.uris, qualified_name
+        ),
+        (None, None) if c.auth.is_some() => {
+            return Err(Box::new(build_401("Token is required".to_owned())))
+        }
+        (None, None) => {
+            return Err(Box::new(build_401(
+                "Client certificate is required".to_owned(),
+            )))
+        }
+    };
+    Err(Box::new(build_error(
+        StatusCode::FORBIDDEN,
+        ErrorResponse::new(ErrorId::Forbidden, message),
+    )))
+}
+
+// Moves the "/ns/:namespace" prefix of the path into request extensions, so that every route is
+// served the same way with and without it.
+fn strip_namespace(req: &mut Request<Bytes>) -> Result<(), Box<Response<Body>>> {
+    let path = req.uri().path();
+    if !path.starts_with("/ns/") {
+        return Ok(());
+    }
+    let rest = &path["/ns/".len()..];
+    let (namespace, rest) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    if !NAMESPACE_RE.is_match(namespace) {
+        return Err(Box::new(build_400(
+            ErrorId::InvalidRequest,
+            format!("Invalid namespace: {}", namespace),
+        )));
+    }
+    let namespace = Namespace(namespace.to_owned());
+    let path_and_query = match req.uri().query() {
+        Some(q) => format!("{}?{}", rest, q),
+        None => rest.to_owned(),
+    };
+    *req.uri_mut() = match path_and_query.parse() {
+        Ok(v) => v,
+        Err(e) => return Err(Box::new(build_400(ErrorId::InvalidRequest, e.to_string()))),
+    };
+    req.extensions_mut().insert(namespace);
+    Ok(())
+}
+
+fn request_namespace(req: &Request<Bytes>) -> Option<String> {
+    req.extensions()
+        .get::<Namespace>()
+        .map(|ns| ns.0.to_owned())
+}
+
+// Returns the path pattern of the route for metrics labels, so that they do not contain service
+// names or addresses.
+fn route_name(path: &str) -> &'static str {
+    // Namespaced routes share the labels of the ones without the prefix.
+    let path = match path
+        .strip_prefix("/ns/")
+        .and_then(|rest| rest.find('/').map(|i| &rest[i..]))
+    {
+        Some(rest) => rest,
+        None => path,
+    };
+    match path {
+        "/" => "/",
+        "/hc" => "/hc",
+        "/metrics" => "/metrics",
+        "/v1/services" => "/v1/services",
+        "/v1/admin/quarantine" => "/v1/admin/quarantine",
+        "/v2/discovery:endpoints" => "/v2/discovery:endpoints",
+        "/v3/discovery:endpoints" => "/v3/discovery:endpoints",
+        "/v2/discovery:clusters" => "/v2/discovery:clusters",
+        _ => {
+            if path.starts_with("/v1/revisions/") {
+                return "/v1/revisions/:service";
+            }
+            if !path.starts_with("/v1/registration/") {
+                return "unknown";
+            }
+            let segments: Vec<&str> = path["/v1/registration/".len()..]
+                .trim_end_matches('/')
+                .split('/')
+                .collect();
+            match segments.as_slice() {
+                [_] => "/v1/registration/:service",
+                [_, _] => "/v1/registration/:service/:ip_port",
+                [_, _, "heartbeat"] => "/v1/registration/:service/:ip_port/heartbeat",
+                [_, _, "status"] => "/v1/registration/:service/:ip_port/status",
+                _ => "unknown",
+            }
+        }
     }
 }
 
-fn route_get_req<S: Storage>(s: &S, req: Request<Body>) -> BoxFut {
+async fn route_get_req<S: Storage>(s: &S, c: &Config, req: Request<Bytes>) -> Response<Body> {
     lazy_static! {
         static ref RE: Regex = Regex::new(r"^/v1/registration/([^/]+)/?$").unwrap();
+        static ref REVISIONS_RE: Regex = Regex::new(r"^/v1/revisions/([^/]+)/?$").unwrap();
     }
 
     let uri = req.uri().to_owned();
     match uri.path() {
         "/" => show_usage(req),
         "/hc" => check_health(req),
-        _ => match RE.captures(uri.path()) {
-            Some(caps) => match caps.get(1) {
-                Some(m) => get_registration(s, req, m.as_str()),
-                _ => res_404(),
-            },
-            _ => res_404(),
-        },
+        "/metrics" => show_metrics(req),
+        "/v1/services" => list_services(s, req).await,
+        "/v1/admin/quarantine" => list_quarantine(req),
+        path => {
+            if let Some(m) = REVISIONS_RE.captures(path).and_then(|caps| caps.get(1)) {
+                return get_revision_weights(s, c, req, m.as_str()).await;
+            }
+            match RE.captures(path) {
+                Some(caps) => match caps.get(1) {
+                    Some(m) => get_registration(s, c, req, m.as_str()).await,
+                    _ => build_404(),
+                },
+                _ => build_404(),
+            }
+        }
     }
 }
 
-fn route_post_req<S: Storage>(s: S, req: Request<Body>) -> BoxFut {
+async fn route_post_req<S: Storage>(s: &S, c: &Config, req: Request<Bytes>) -> Response<Body> {
     lazy_static! {
         static ref RE: Regex = Regex::new(r"^/v1/registration/([^/]+)/?$").unwrap();
     }
@@ -125,244 +500,1250 @@ fn route_post_req<S: Storage>(s: S, req: Request<Body>) -> BoxFut {
     match uri.path() {
         "/" => show_usage(req),
         "/hc" => check_health(req),
-        "/v2/discovery:endpoints" => get_registration_v2(&s, req),
+        "/v2/discovery:endpoints" => get_registration_xds(s, c, req, v2xds::EDS_TYPE_URL).await,
+        "/v3/discovery:endpoints" => get_registration_xds(s, c, req, v3xds::EDS_TYPE_URL).await,
+        "/v2/discovery:clusters" => get_clusters(s, c, req).await,
         _ => match RE.captures(uri.path()) {
             Some(caps) => match caps.get(1) {
-                Some(m) => register_hosts(s, req, m.as_str()),
-                _ => res_404(),
+                Some(m) => register_hosts(s, c, req, m.as_str()).await,
+                _ => build_404(),
             },
-            _ => res_404(),
+            _ => build_404(),
         },
     }
 }
 
-fn route_delete_req<S: Storage>(s: &S, req: Request<Body>) -> BoxFut {
+async fn route_put_req<S: Storage>(s: &S, c: &Config, req: Request<Bytes>) -> Response<Body> {
+    lazy_static! {
+        static ref RE: Regex = Regex::new(
+            r"^/v1/registration/([^/]+)/(\[[^/\]]+\]|[^/:\[\]]+):([^/:]+)/(heartbeat|status)/?$"
+        )
+        .unwrap();
+        static ref REVISIONS_RE: Regex = Regex::new(r"^/v1/revisions/([^/]+)/?$").unwrap();
+    }
+
+    let path = decode_ip_port_path(req.uri().path());
+    if let Some(m) = REVISIONS_RE.captures(&path).and_then(|caps| caps.get(1)) {
+        return put_revision_weights(s, c, req, m.as_str()).await;
+    }
+    match RE.captures(&path) {
+        Some(caps) => match (caps.get(1), caps.get(2), caps.get(3), caps.get(4)) {
+            (Some(m_service), Some(m_ip), Some(m_port), Some(m_action)) => {
+                let name = m_service.as_str();
+                let ip = trim_brackets(m_ip.as_str()).to_string();
+                match m_action.as_str() {
+                    "heartbeat" => heartbeat_host(s, c, req, name, ip, m_port.as_str()).await,
+                    _ => update_host_status(s, c, req, name, ip, m_port.as_str()).await,
+                }
+            }
+            _ => build_404(),
+        },
+        _ => build_404(),
+    }
+}
+
+async fn route_delete_req<S: Storage>(s: &S, c: &Config, req: Request<Bytes>) -> Response<Body> {
     lazy_static! {
         static ref RE: Regex =
-            Regex::new(r"^/v1/registration/([^/]+)/([^/:]+):([^/:]+)/?$").unwrap();
+            Regex::new(r"^/v1/registration/([^/]+)/(\[[^/\]]+\]|[^/:\[\]]+):([^/:]+)/?$").unwrap();
     }
 
-    let uri = req.uri().to_owned();
-    match uri.path() {
+    let path = decode_ip_port_path(req.uri().path());
+    match path.as_str() {
         "/" => show_usage(req),
         "/hc" => check_health(req),
-        _ => match RE.captures(uri.path()) {
+        _ => match RE.captures(&path) {
             Some(caps) => match caps.get(1) {
                 Some(m_service) => match caps.get(2) {
                     Some(m_ip) => match caps.get(3) {
-                        Some(m_port) => delete_host(
-                            s,
-                            m_service.as_str(),
-                            m_ip.as_str().to_string(),
-                            m_port.as_str(),
-                        ),
-                        _ => res_404(),
+                        Some(m_port) => {
+                            delete_host(
+                                s,
+                                c,
+                                req,
+                                m_service.as_str(),
+                                trim_brackets(m_ip.as_str()).to_string(),
+                                m_port.as_str(),
+                            )
+                            .await
+                        }
+                        _ => build_404(),
                     },
-                    _ => res_404(),
+                    _ => build_404(),
                 },
-                _ => res_404(),
+                _ => build_404(),
             },
-            _ => res_404(),
+            _ => build_404(),
         },
     }
 }
 
-fn get_registration<S: Storage>(s: &S, _: Request<Body>, name: &str) -> BoxFut {
-    let hosts = match s.query_items(name) {
+// Clients may percent-encode the brackets and colons of an IPv6 address in the path.
+fn decode_ip_port_path(path: &str) -> String {
+    lazy_static! {
+        static ref RE: Regex = Regex::new(r"%(5[BbDd]|3[Aa])").unwrap();
+    }
+
+    RE.replace_all(path, |caps: &Captures| {
+        match caps[1].to_ascii_uppercase().as_str() {
+            "5B" => "[",
+            "5D" => "]",
+            _ => ":",
+        }
+    })
+    .into_owned()
+}
+
+// Returns the namespace of the path and the environment given by `?env=`, or the configured one
+// when missing. The service name is checked here, since every key of a service is built from the
+// scope and a name with '/' or '@' would address another namespace or environment.
+fn request_scope(
+    c: &Config,
+    req: &Request<Bytes>,
+    name: &str,
+) -> Result<Scope, Box<Response<Body>>> {
+    if !SERVICE_NAME_RE.is_match(name) {
+        return Err(Box::new(build_400(
+            ErrorId::InvalidRequest,
+            format!("Invalid service name: {}", name),
+        )));
+    }
+    let env = req
+        .uri()
+        .query()
+        .unwrap_or("")
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(k, _)| *k == "env")
+        .map(|(_, v)| v.to_owned());
+    let env = match env {
+        Some(v) if !ENV_RE.is_match(&v) => {
+            return Err(Box::new(build_400(
+                ErrorId::InvalidRequest,
+                format!("Invalid env: {}", v),
+            )))
+        }
+        Some(v) => v,
+        None => c.env.to_owned(),
+    };
+    Ok(Scope {
+        namespace: request_namespace(req),
+        env,
+    })
+}
+
+async fn get_registration<S: Storage>(
+    s: &S,
+    c: &Config,
+    req: Request<Bytes>,
+    name: &str,
+) -> Response<Body> {
+    let scope = match request_scope(c, &req, name) {
         Ok(v) => v,
-        Err(e) => return res_500(e.to_string()),
+        Err(res) => return *res,
     };
+    let key = scope.key(name);
+    let (mut hosts, weights) =
+        match future::try_join(s.query_items(&key), s.query_revision_weights(&key)).await {
+            Ok(v) => v,
+            Err(e) => return build_storage_error(e),
+        };
+    // Backends fill in the storage key, which includes the namespace and the environment.
+    for h in hosts.iter_mut() {
+        h.service = name.to_owned();
+    }
     let registration = Registration {
         service: name.to_owned(),
-        env: "production".to_owned(),
-        hosts,
+        namespace: scope.namespace,
+        env: scope.env,
+        hosts: filter_active_revisions(hosts, &weights),
     };
     let body = match serde_json::to_string(&registration) {
         Ok(v) => v,
-        Err(e) => return res_500(e.to_string()),
+        Err(e) => return build_500(e.to_string()),
     };
     info!("Build 200 response: body-size={}", body.len());
-    wrap_future(Response::new(Body::from(body)))
-}
-
-fn get_registration_v2<S: Storage>(s: &S, req: Request<Body>) -> BoxFut {
-    let st = s.clone();
-    let f = req
-        .into_body()
-        .concat2()
-        .map(move |buffer| match str::from_utf8(&buffer) {
-            Ok(body) => match serde_json::from_str::<DiscoveryRequest>(&body) {
-                Ok(d_req) => {
-                    let mut resources = Vec::new();
-                    for name in &d_req.resource_names {
-                        let hosts = match st.query_items(&name) {
-                            Ok(v) => v,
-                            Err(e) => return build_500(e.to_string()),
-                        };
-                        let lle_vec = hosts_to_locality_lb_endpoints(hosts);
-                        resources.push(ClusterLoadAssignment {
-                            type_url: EDS_TYPE_URL.to_string(),
-                            cluster_name: name.to_owned(),
-                            endpoints: lle_vec,
-                        });
-                    }
-
-                    let d_res = EdsDiscoveryResponse {
-                        version_info: Uuid::new_v4().to_string(),
-                        resources,
-                    };
-                    let body = match serde_json::to_string(&d_res) {
-                        Ok(v) => v,
-                        Err(e) => return build_500(e.to_string()),
-                    };
-                    info!("Build 200 response: body-size={}", body.len());
-                    Response::new(Body::from(body))
-                }
-                Err(m) => {
-                    let mut msg = "Invalid JSON string: ".to_owned();
-                    msg.push_str(&m.to_string());
-                    debug!("invalid json: {:?}", msg);
-                    debug!("invalid request: {:?}", body);
-                    build_400(msg)
-                }
-            },
-            Err(_) => build_400("Invalid UTF-8 string".to_owned()),
-        });
-    Box::new(f)
-}
-
-fn register_hosts<S: Storage>(s: S, req: Request<Body>, name: &str) -> BoxFut {
-    let st = s.clone();
-    let name = name.to_owned();
-    let f = req
-        .into_body()
-        .concat2()
-        .map(move |buffer| match str::from_utf8(&buffer) {
-            Ok(body) => match serde_json::from_str::<RegistrationParam>(&body) {
-                Ok(param) => {
-                    let host = match convert_param_to_host(&name, param, s.ttl()) {
-                        Ok(v) => v,
-                        Err(_) => {
-                            error!("Failed to fetch system time");
-                            return build_500("Failed to fetch system time".to_owned());
-                        }
-                    };
-                    if let Err(e) = st.store_item(&name, host) {
-                        return build_500(e.to_string());
-                    }
-
-                    info!("Build 202 response");
-                    Response::builder()
-                        .status(StatusCode::ACCEPTED)
-                        .body(Body::empty())
-                        .unwrap()
-                }
-                Err(m) => {
-                    let mut msg = "Invalid JSON string: ".to_owned();
-                    msg.push_str(&m.to_string());
-                    build_400(msg)
-                }
-            },
-            Err(_) => build_400("Invalid UTF-8 string".to_owned()),
-        });
-    Box::new(f)
+    Response::new(Body::from(body))
+}
+
+async fn list_services<S: Storage>(s: &S, req: Request<Bytes>) -> Response<Body> {
+    let namespace = request_namespace(&req);
+    let mut services = match s.list_services().await {
+        Ok(v) => v,
+        Err(e) => return build_storage_error(e),
+    };
+    // Every namespace is listed without the prefix.
+    if namespace.is_some() {
+        services.retain(|s| s.namespace == namespace);
+    }
+    let body = match serde_json::to_string(&Services { services }) {
+        Ok(v) => v,
+        Err(e) => return build_500(e.to_string()),
+    };
+    info!("Build 200 response: body-size={}", body.len());
+    Response::new(Body::from(body))
+}
+
+// Lists corrupt items skipped by queries when SKIP_CORRUPT_ITEMS is enabled.
+fn list_quarantine(req: Request<Bytes>) -> Response<Body> {
+    let namespace = request_namespace(&req);
+    let mut items = quarantine::list();
+    // Every namespace is listed without the prefix.
+    if namespace.is_some() {
+        items.retain(|item| split_service_key(&item.service).0 == namespace.as_deref());
+    }
+    let body = match serde_json::to_string(&Quarantine { items }) {
+        Ok(v) => v,
+        Err(e) => return build_500(e.to_string()),
+    };
+    info!("Build 200 response: body-size={}", body.len());
+    Response::new(Body::from(body))
+}
+
+// Serves both v2 and v3 EDS. The API version is selected by the request's type_url, falling back
+// to the one of the requested path.
+async fn get_registration_xds<S: Storage>(
+    s: &S,
+    c: &Config,
+    req: Request<Bytes>,
+    default_type_url: &'static str,
+) -> Response<Body> {
+    let namespace = request_namespace(&req);
+    let d_req = match read_json_body::<DiscoveryRequest>(req) {
+        Ok(v) => v,
+        Err(res) => return *res,
+    };
+    warn_if_nack(&d_req);
+    if let Some(name) = d_req.resource_names.iter().find(|name| {
+        qualify_cluster_name(namespace.as_deref(), name)
+            .and_then(|name| cluster_key(c, &name))
+            .is_none()
+    }) {
+        return build_400(
+            ErrorId::InvalidRequest,
+            format!("Invalid cluster name: {}", name),
+        );
+    }
+
+    let type_url = match d_req.type_url.as_deref() {
+        None | Some("") => default_type_url,
+        Some(v2xds::EDS_TYPE_URL) => v2xds::EDS_TYPE_URL,
+        Some(v3xds::EDS_TYPE_URL) => v3xds::EDS_TYPE_URL,
+        Some(t) => {
+            return build_400(
+                ErrorId::InvalidRequest,
+                format!("Unsupported type_url: {}", t),
+            )
+        }
+    };
+
+    let resources = match build_cluster_load_assignments(
+        s,
+        c,
+        namespace.as_deref(),
+        &d_req.resource_names,
+        d_req.node.locality.as_ref(),
+        type_url,
+    )
+    .await
+    {
+        Ok(v) => v,
+        Err(e) => return build_storage_error(e),
+    };
+
+    let version_info = match build_version_info(&resources) {
+        Ok(v) => v,
+        Err(e) => return build_500(e.to_string()),
+    };
+    if d_req.version_info.as_ref() == Some(&version_info) {
+        return build_304(&version_info);
+    }
+
+    let d_res = EdsDiscoveryResponse {
+        version_info,
+        resources,
+        type_url: type_url.to_string(),
+        nonce: Uuid::new_v4().to_string(),
+    };
+    let body = match serde_json::to_string(&d_res) {
+        Ok(v) => v,
+        Err(e) => return build_500(e.to_string()),
+    };
+    info!(
+        "Build 200 response: body-size={}, version_info={}, nonce={}",
+        body.len(),
+        d_res.version_info,
+        d_res.nonce
+    );
+    Response::new(Body::from(body))
+}
+
+async fn get_clusters<S: Storage>(s: &S, c: &Config, req: Request<Bytes>) -> Response<Body> {
+    let namespace = request_namespace(&req);
+    let d_req = match read_json_body::<DiscoveryRequest>(req) {
+        Ok(v) => v,
+        Err(res) => return *res,
+    };
+    warn_if_nack(&d_req);
+
+    let services = match s.list_services().await {
+        Ok(v) => v,
+        Err(e) => return build_storage_error(e),
+    };
+    let locality_weighted_lb = !c.eds.locality_weights.is_empty();
+    // Envoy sends empty resource_names to fetch every cluster.
+    let resources: Vec<Cluster> = services
+        .iter()
+        .filter(|s| s.env == c.env && s.healthy_hosts > 0)
+        .filter(|s| namespace.is_none() || s.namespace == namespace)
+        .map(cluster_name)
+        .filter(|name| d_req.resource_names.is_empty() || d_req.resource_names.contains(name))
+        .map(|name| build_eds_cluster(&name, &c.cds, locality_weighted_lb))
+        .collect();
+
+    let version_info = match build_version_info(&resources) {
+        Ok(v) => v,
+        Err(e) => return build_500(e.to_string()),
+    };
+    if d_req.version_info.as_ref() == Some(&version_info) {
+        return build_304(&version_info);
+    }
+
+    let d_res = CdsDiscoveryResponse {
+        version_info,
+        resources,
+        type_url: v2xds::CDS_TYPE_URL.to_string(),
+        nonce: Uuid::new_v4().to_string(),
+    };
+    let body = match serde_json::to_string(&d_res) {
+        Ok(v) => v,
+        Err(e) => return build_500(e.to_string()),
+    };
+    info!(
+        "Build 200 response: body-size={}, version_info={}, nonce={}",
+        body.len(),
+        d_res.version_info,
+        d_res.nonce
+    );
+    Response::new(Body::from(body))
+}
+
+// Clusters of namespaced services are named like "namespace/service".
+fn cluster_name(s: &ServiceSummary) -> String {
+    match s.namespace {
+        Some(ref ns) => format!("{}/{}", ns, s.service),
+        None => s.service.to_owned(),
+    }
+}
+
+// Builds the assignment of each cluster for a node, shared by the REST and the streaming EDS.
+// Names without a namespace refer to services in `namespace`, but responses keep them as
+// requested since Envoy matches assignments by the name it asked for. Names of another namespace
+// have no hosts.
+pub(crate) async fn build_cluster_load_assignments<S: Storage>(
+    s: &S,
+    c: &Config,
+    namespace: Option<&str>,
+    names: &[String],
+    node_locality: Option<&Locality>,
+    type_url: &str,
+) -> Result<Vec<ClusterLoadAssignment>, S::E> {
+    let qualified_names: Vec<Option<String>> = names
+        .iter()
+        .map(|name| qualify_cluster_name(namespace, name))
+        .collect();
+    // Query every requested cluster concurrently. Invalid names have no hosts.
+    let queries = qualified_names.iter().map(|name| async move {
+        match name.as_deref().and_then(|name| cluster_key(c, name)) {
+            Some(key) => {
+                future::try_join(s.query_items(&key), s.query_revision_weights(&key)).await
+            }
+            None => Ok((Vec::new(), RevisionWeights::default())),
+        }
+    });
+    let hosts_list = future::try_join_all(queries).await?;
+    Ok(names
+        .iter()
+        .zip(qualified_names.iter())
+        .zip(hosts_list)
+        .map(|((name, qualified_name), (hosts, weights))| {
+            let mut endpoints = hosts_to_locality_lb_endpoints(hosts, node_locality, &c.eds);
+            // Canary percentage is applied on top, keeping the ratio among revisions.
+            let mut group_shares = vec![GroupShares::revisions(&weights)];
+            if let Some(p) = qualified_name
+                .as_ref()
+                .and_then(|name| c.eds.services.get(name))
+                .and_then(|s| s.canary_percentage)
+            {
+                group_shares.push(GroupShares::canary(p));
+            }
+            apply_group_shares(&mut endpoints, &group_shares);
+            ClusterLoadAssignment {
+                type_url: type_url.to_string(),
+                cluster_name: name.to_owned(),
+                endpoints,
+            }
+        })
+        .collect())
+}
+
+// Returns None for names of another namespace, which are out of reach of prefixed requests.
+fn qualify_cluster_name(namespace: Option<&str>, cluster_name: &str) -> Option<String> {
+    match (namespace, cluster_name.split_once('/')) {
+        (Some(ns), None) => Some(format!("{}/{}", ns, cluster_name)),
+        (Some(ns), Some((name_ns, _))) if ns != name_ns => None,
+        _ => Some(cluster_name.to_owned()),
+    }
+}
+
+// Returns the storage key of a cluster named by cluster_name, or None for names that no service
+// could be registered with, like ones with '@' that would address another environment.
+pub(crate) fn cluster_key(c: &Config, cluster_name: &str) -> Option<String> {
+    let (namespace, service) = match cluster_name.split_once('/') {
+        Some((ns, service)) => (Some(ns), service),
+        None => (None, cluster_name),
+    };
+    if namespace.is_some_and(|ns| !NAMESPACE_RE.is_match(ns)) || !SERVICE_NAME_RE.is_match(service)
+    {
+        return None;
+    }
+    Some(build_service_key(namespace, service, &c.env))
+}
+
+fn warn_if_nack(d_req: &DiscoveryRequest) {
+    if let Some(ref status) = d_req.error_detail {
+        warn!(
+            "Receive NACK: node={}, version_info={:?}, response_nonce={:?}, code={}, message={}",
+            d_req.node.id, d_req.version_info, d_req.response_nonce, status.code, status.message
+        );
+    }
+}
+
+async fn register_hosts<S: Storage>(
+    s: &S,
+    c: &Config,
+    req: Request<Bytes>,
+    name: &str,
+) -> Response<Body> {
+    if let Err(res) = authorize_write(c, &req, name) {
+        return *res;
+    }
+    let mut scope = match request_scope(c, &req, name) {
+        Ok(v) => v,
+        Err(res) => return *res,
+    };
+    let mut param = match read_json_body::<RegistrationParam>(req) {
+        Ok(v) => v,
+        Err(res) => return *res,
+    };
+    if let Some(env) = param.env.take() {
+        scope.env = env;
+    }
+    let fields = validate_registration(&scope.env, &param);
+    if !fields.is_empty() {
+        let mut r = ErrorResponse::new(
+            ErrorId::InvalidRegistration,
+            "Invalid registration".to_owned(),
+        );
+        r.fields = fields;
+        return build_error(StatusCode::BAD_REQUEST, r);
+    }
+    let host = match convert_param_to_host(name, param, s.ttl()) {
+        Ok(v) => v,
+        Err(_) => {
+            error!("Failed to fetch system time");
+            return build_500("Failed to fetch system time".to_owned());
+        }
+    };
+
+    if let Err(e) = s.store_item(&scope.key(name), host).await {
+        return build_storage_error(e);
+    }
+
+    info!("Build 202 response");
+    Response::builder()
+        .status(StatusCode::ACCEPTED)
+        .body(Body::default())
+        .unwrap()
 }
 
-fn delete_host<S: Storage>(s: &S, name: &str, ip: String, port_string: &str) -> BoxFut {
+async fn delete_host<S: Storage>(
+    s: &S,
+    c: &Config,
+    req: Request<Bytes>,
+    name: &str,
+    ip: String,
+    port_string: &str,
+) -> Response<Body> {
+    if let Err(res) = authorize_write(c, &req, name) {
+        return *res;
+    }
+    let scope = match request_scope(c, &req, name) {
+        Ok(v) => v,
+        Err(res) => return *res,
+    };
     let port = match port_string.parse() {
         Ok(v) => v,
-        Err(_e) => return res_400(format!("Given port is invalid as integer: {}", port_string)),
+        Err(_e) => {
+            return build_400(
+                ErrorId::InvalidPort,
+                format!("Given port is invalid as integer: {}", port_string),
+            )
+        }
     };
 
-    match s.delete_item(name, ip, port) {
+    match s.delete_item(&scope.key(name), ip, port).await {
         Ok(res) => {
             if res.is_none() {
-                let r = ErrorResponse {
-                    id: ErrorId::HostNotFound,
-                    reason: "Not found the entry".to_owned(),
-                };
-                let body = match serde_json::to_string(&r) {
-                    Ok(v) => v,
-                    Err(e) => return res_500(e.to_string()),
-                };
-                return res_400(body);
+                return build_400(ErrorId::HostNotFound, "Not found the entry".to_owned());
             }
         }
-        Err(e) => return res_500(e.to_string()),
+        Err(e) => return build_storage_error(e),
     }
 
     info!("Build 202 response");
-    wrap_future(
-        Response::builder()
-            .status(StatusCode::ACCEPTED)
-            .body(Body::empty())
-            .unwrap(),
-    )
+    Response::builder()
+        .status(StatusCode::ACCEPTED)
+        .body(Body::default())
+        .unwrap()
 }
 
-fn convert_param_to_host(
+async fn heartbeat_host<S: Storage>(
+    s: &S,
+    c: &Config,
+    req: Request<Bytes>,
     name: &str,
-    p: RegistrationParam,
-    ttl: u64,
-) -> Result<Host, time::SystemTimeError> {
+    ip: String,
+    port_string: &str,
+) -> Response<Body> {
+    if let Err(res) = authorize_write(c, &req, name) {
+        return *res;
+    }
+    let scope = match request_scope(c, &req, name) {
+        Ok(v) => v,
+        Err(res) => return *res,
+    };
+    let port = match port_string.parse() {
+        Ok(v) => v,
+        Err(_e) => {
+            return build_400(
+                ErrorId::InvalidPort,
+                format!("Given port is invalid as integer: {}", port_string),
+            )
+        }
+    };
+    let (last_check_in, expire_time) = match build_check_in(s.ttl()) {
+        Ok(v) => v,
+        Err(_) => {
+            error!("Failed to fetch system time");
+            return build_500("Failed to fetch system time".to_owned());
+        }
+    };
+
+    let key = scope.key(name);
+    match s
+        .refresh_item(&key, ip, port, last_check_in, expire_time)
+        .await
+    {
+        Ok(res) => {
+            if res.is_none() {
+                return build_error(
+                    StatusCode::NOT_FOUND,
+                    ErrorResponse::new(ErrorId::HostNotFound, "Not found the entry".to_owned()),
+                );
+            }
+        }
+        Err(e) => return build_storage_error(e),
+    }
+
+    info!("Build 202 response");
+    Response::builder()
+        .status(StatusCode::ACCEPTED)
+        .body(Body::default())
+        .unwrap()
+}
+
+async fn update_host_status<S: Storage>(
+    s: &S,
+    c: &Config,
+    req: Request<Bytes>,
+    name: &str,
+    ip: String,
+    port_string: &str,
+) -> Response<Body> {
+    if let Err(res) = authorize_write(c, &req, name) {
+        return *res;
+    }
+    let scope = match request_scope(c, &req, name) {
+        Ok(v) => v,
+        Err(res) => return *res,
+    };
+    let port = match port_string.parse() {
+        Ok(v) => v,
+        Err(_e) => {
+            return build_400(
+                ErrorId::InvalidPort,
+                format!("Given port is invalid as integer: {}", port_string),
+            )
+        }
+    };
+    let param = match read_json_body::<StatusParam>(req) {
+        Ok(v) => v,
+        Err(res) => return *res,
+    };
+
+    let key = scope.key(name);
+    match s.update_status(&key, ip, port, param.status).await {
+        Ok(res) => {
+            if res.is_none() {
+                return build_error(
+                    StatusCode::NOT_FOUND,
+                    ErrorResponse::new(ErrorId::HostNotFound, "Not found the entry".to_owned()),
+                );
+            }
+        }
+        Err(e) => return build_storage_error(e),
+    }
+
+    info!("Build 202 response");
+    Response::builder()
+        .status(StatusCode::ACCEPTED)
+        .body(Body::default())
+        .unwrap()
+}
+
+async fn get_revision_weights<S: Storage>(
+    s: &S,
+    c: &Config,
+    req: Request<Bytes>,
+    name: &str,
+) -> Response<Body> {
+    let scope = match request_scope(c, &req, name) {
+        Ok(v) => v,
+        Err(res) => return *res,
+    };
+    let weights = match s.query_revision_weights(&scope.key(name)).await {
+        Ok(v) => v,
+        Err(e) => return build_storage_error(e),
+    };
+    let body = match serde_json::to_string(&RevisionWeightsParam { weights }) {
+        Ok(v) => v,
+        Err(e) => return build_500(e.to_string()),
+    };
+    info!("Build 200 response: body-size={}", body.len());
+    Response::new(Body::from(body))
+}
+
+async fn put_revision_weights<S: Storage>(
+    s: &S,
+    c: &Config,
+    req: Request<Bytes>,
+    name: &str,
+) -> Response<Body> {
+    if let Err(res) = authorize_write(c, &req, name) {
+        return *res;
+    }
+    let scope = match request_scope(c, &req, name) {
+        Ok(v) => v,
+        Err(res) => return *res,
+    };
+    let param = match read_json_body::<RevisionWeightsParam>(req) {
+        Ok(v) => v,
+        Err(res) => return *res,
+    };
+    let fields = validate_revision_weights(&param);
+    if !fields.is_empty() {
+        let mut r = ErrorResponse::new(
+            ErrorId::InvalidRevisionWeights,
+            "Invalid revision weights".to_owned(),
+        );
+        r.fields = fields;
+        return build_error(StatusCode::BAD_REQUEST, r);
+    }
+
+    let key = scope.key(name);
+    if let Err(e) = s.store_revision_weights(&key, param.weights).await {
+        return build_storage_error(e);
+    }
+
+    info!("Build 202 response");
+    Response::builder()
+        .status(StatusCode::ACCEPTED)
+        .body(Body::default())
+        .unwrap()
+}
+
+// Hosts of inactive revisions are left out while any host of an active revision is registered.
+fn filter_active_revisions(hosts: Vec<Host>, weights: &RevisionWeights) -> Vec<Host> {
+    let is_active = |h: &Host| weights.get(&h.revision).is_some_and(|w| *w > 0);
+    if weights.is_empty() || !hosts.iter().any(is_active) {
+        return hosts;
+    }
+    hosts.into_iter().filter(is_active).collect()
+}
+
+// Parses the request body read by dispatch as JSON, or returns a 400 response.
+fn read_json_body<T: DeserializeOwned>(req: Request<Bytes>) -> Result<T, Box<Response<Body>>> {
+    parse_json_body(req.body())
+}
+
+// Parses a JSON request body, or returns a 400 response.
+fn parse_json_body<T: DeserializeOwned>(buffer: &[u8]) -> Result<T, Box<Response<Body>>> {
+    match str::from_utf8(buffer) {
+        Ok(body) => match serde_json::from_str::<T>(body) {
+            Ok(v) => Ok(v),
+            Err(m) => {
+                let mut msg = "Invalid JSON string: ".to_owned();
+                msg.push_str(&m.to_string());
+                debug!("invalid json: {:?}", msg);
+                debug!("invalid request: {:?}", body);
+                Err(Box::new(build_400(ErrorId::InvalidJson, msg)))
+            }
+        },
+        Err(_) => Err(Box::new(build_400(
+            ErrorId::InvalidJson,
+            "Invalid UTF-8 string".to_owned(),
+        ))),
+    }
+}
+
+const RESERVED_LABEL_NAMES: &[&str] = &["canary", "revision", "instance_id"];
+
+// Only the ratio among weights matters, so this leaves room for shares as fine as 0.01%.
+const MAX_REVISION_WEIGHT: u32 = 10_000;
+
+lazy_static! {
+    static ref SERVICE_NAME_RE: Regex = Regex::new(r"^[A-Za-z0-9_.-]{1,128}$").unwrap();
+    static ref ENV_RE: Regex = Regex::new(r"^[A-Za-z0-9_-]{1,64}$").unwrap();
+    static ref NAMESPACE_RE: Regex = Regex::new(r"^[A-Za-z0-9_-]{1,64}$").unwrap();
+}
+
+// Returns every invalid field, so that a client can fix all of them at once.
+fn validate_registration(env: &str, p: &RegistrationParam) -> Vec<FieldError> {
+    lazy_static! {
+        static ref LABEL_NAME_RE: Regex = Regex::new(r"^[A-Za-z0-9_.-]{1,63}$").unwrap();
+    }
+
+    let mut fields = Vec::new();
+    if !ENV_RE.is_match(env) {
+        fields.push(FieldError::new(
+            "env",
+            "must be 1 to 64 characters of alphanumerics, '_' or '-'",
+        ));
+    }
+    if trim_brackets(&p.ip).parse::<IpAddr>().is_err() {
+        fields.push(FieldError::new("ip", "must be an IPv4 or IPv6 address"));
+    }
+    if p.port == 0 {
+        fields.push(FieldError::new("port", "must not be 0"));
+    }
+    if p.revision.is_empty() {
+        fields.push(FieldError::new("revision", "must not be empty"));
+    }
+    for (field, value) in &[
+        ("tags.az", &p.tags.az),
+        ("tags.region", &p.tags.region),
+        ("tags.instance_id", &p.tags.instance_id),
+    ] {
+        if value.is_empty() {
+            fields.push(FieldError::new(field, "must not be empty"));
+        }
+    }
+    // Envoy rejects weights out of this range.
+    if let Some(weight) = p.tags.load_balancing_weight {
+        if !(1..=128).contains(&weight) {
+            fields.push(FieldError::new(
+                "tags.load_balancing_weight",
+                "must be between 1 and 128",
+            ));
+        }
+    }
+    // Labels share the "envoy.lb" namespace with the fixed fields.
+    for name in p.tags.labels.keys() {
+        let field = format!("tags.labels.{}", name);
+        if !LABEL_NAME_RE.is_match(name) {
+            fields.push(FieldError::new(
+                &field,
+                "must be 1 to 63 characters of alphanumerics, '_', '.' or '-'",
+            ));
+        } else if RESERVED_LABEL_NAMES.contains(&name.as_str()) {
+            fields.push(FieldError::new(&field, "is reserved"));
+        }
+    }
+    fields
+}
+
+fn validate_revision_weights(p: &RevisionWeightsParam) -> Vec<FieldError> {
+    let mut fields = Vec::new();
+    for (revision, weight) in p.weights.iter() {
+        let field = format!("weights.{}", revision);
+        if revision.is_empty() {
+            fields.push(FieldError::new(&field, "revision must not be empty"));
+        }
+        if *weight > MAX_REVISION_WEIGHT {
+            fields.push(FieldError::new(&field, "must be between 0 and 10000"));
+        }
+    }
+    fields
+}
+
+// Returns last_check_in and expire_time for a host checking in now.
+fn build_check_in(ttl: u64) -> Result<(String, u64), time::SystemTimeError> {
     let last_check_in = chrono::Utc::now()
         .format("%Y-%m-%d %H:%M:%S%:z")
         .to_string();
     let expire_time = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() + ttl;
+    Ok((last_check_in, expire_time))
+}
+
+fn convert_param_to_host(
+    name: &str,
+    p: RegistrationParam,
+    ttl: u64,
+) -> Result<Host, time::SystemTimeError> {
+    let (last_check_in, expire_time) = build_check_in(ttl)?;
     Ok(Host {
-        ip_address: p.ip,
+        ip_address: trim_brackets(&p.ip).to_owned(),
         port: p.port,
         last_check_in,
         expire_time,
         revision: p.revision,
         service: name.to_owned(),
         tags: p.tags,
+        health_status: HealthStatus::Healthy,
     })
 }
 
-fn show_usage(_: Request<Body>) -> BoxFut {
+fn show_usage(_: Request<Bytes>) -> Response<Body> {
     let usage = "GET /v1/registration/:service, POST /v1/registration/:service, DELETE \
-                 /v1/registration/:service/:ip_address";
-    wrap_future(Response::new(Body::from(usage)))
+                 /v1/registration/:service/:ip_address, PUT \
+                 /v1/registration/:service/:ip_address/heartbeat, PUT \
+                 /v1/registration/:service/:ip_address/status, GET /v1/revisions/:service, PUT \
+                 /v1/revisions/:service";
+    Response::new(Body::from(usage))
 }
 
-fn check_health(_: Request<Body>) -> BoxFut {
-    wrap_future(Response::new(Body::from("ok")))
+fn check_health(_: Request<Bytes>) -> Response<Body> {
+    Response::new(Body::from("ok"))
 }
 
-fn build_400(msg: String) -> Response<Body> {
-    info!("Build 400 response");
+fn show_metrics(_: Request<Bytes>) -> Response<Body> {
+    match metrics::encode() {
+        Ok((content_type, body)) => Response::builder()
+            .header(CONTENT_TYPE, content_type)
+            .body(Body::from(body))
+            .unwrap(),
+        Err(e) => build_500(e.to_string()),
+    }
+}
+
+fn build_304(version_info: &str) -> Response<Body> {
+    info!("Build 304 response: version_info={}", version_info);
     Response::builder()
-        .status(StatusCode::BAD_REQUEST)
-        .body(Body::from(msg))
+        .status(StatusCode::NOT_MODIFIED)
+        .body(Body::default())
         .unwrap()
 }
 
-fn res_400(msg: String) -> BoxFut {
-    wrap_future(build_400(msg))
+fn build_400(id: ErrorId, msg: String) -> Response<Body> {
+    build_error(StatusCode::BAD_REQUEST, ErrorResponse::new(id, msg))
 }
 
-fn res_404() -> BoxFut {
-    wrap_future(
-        Response::builder()
-            .status(StatusCode::NOT_FOUND)
-            .body(Body::empty())
-            .unwrap(),
+fn build_401(msg: String) -> Response<Body> {
+    let mut res = build_error(
+        StatusCode::UNAUTHORIZED,
+        ErrorResponse::new(ErrorId::Unauthorized, msg),
+    );
+    res.headers_mut()
+        .insert(WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+    res
+}
+
+fn build_404() -> Response<Body> {
+    build_error(
+        StatusCode::NOT_FOUND,
+        ErrorResponse::new(ErrorId::RouteNotFound, "Not found the route".to_owned()),
     )
 }
 
 fn build_500(msg: String) -> Response<Body> {
-    info!("Build 500 response: body={}", msg);
+    build_error(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        ErrorResponse::new(ErrorId::InternalError, msg),
+    )
+}
+
+// Throttling and timeouts are transient, so let clients retry after a while.
+fn build_storage_error<E: Into<StorageError>>(e: E) -> Response<Body> {
+    let e = e.into();
+    let (status, id) = match e.kind {
+        ErrorKind::Throttled => (StatusCode::SERVICE_UNAVAILABLE, ErrorId::Throttled),
+        ErrorKind::Timeout => (StatusCode::SERVICE_UNAVAILABLE, ErrorId::Timeout),
+        ErrorKind::Data => (StatusCode::INTERNAL_SERVER_ERROR, ErrorId::CorruptRecord),
+        ErrorKind::Api | ErrorKind::System => {
+            (StatusCode::INTERNAL_SERVER_ERROR, ErrorId::InternalError)
+        }
+    };
+    let mut r = ErrorResponse::new(id, e.msg);
+    r.key = e.key;
+    let mut res = build_error(status, r);
+    if status == StatusCode::SERVICE_UNAVAILABLE {
+        res.headers_mut()
+            .insert(RETRY_AFTER, HeaderValue::from_static(RETRY_AFTER_SECS));
+    }
+    res
+}
+
+fn build_error(status: StatusCode, r: ErrorResponse) -> Response<Body> {
+    info!(
+        "Build {} response: id={:?}, reason={}",
+        status.as_u16(),
+        r.id,
+        r.reason
+    );
+    // Serializing this struct never fails in practice, but fall back to the plain reason.
+    let body = serde_json::to_string(&r).unwrap_or(r.reason);
     Response::builder()
-        .status(StatusCode::INTERNAL_SERVER_ERROR)
-        .body(Body::from(msg))
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(body))
         .unwrap()
 }
 
-fn res_500(msg: String) -> BoxFut {
-    wrap_future(build_500(msg))
-}
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::memory::MemoryStorage;
+    use crate::types::{CdsConfig, EdsConfig};
+
+    fn build_config() -> Config {
+        Config {
+            bind_addr: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            listen_port: 0,
+            env: "production".to_owned(),
+            cds: CdsConfig::default(),
+            eds: EdsConfig::default(),
+            auth: None,
+            tls: None,
+        }
+    }
+
+    async fn request(
+        s: &MemoryStorage,
+        method: Method,
+        path: &str,
+        body: Value,
+    ) -> (StatusCode, Option<Value>) {
+        let body = match body {
+            Value::Null => Bytes::new(),
+            v => Bytes::from(v.to_string()),
+        };
+        let req = Request::builder()
+            .method(method)
+            .uri(path)
+            .body(body)
+            .unwrap();
+        let res = handle(s, &build_config(), req).await;
+        let status = res.status();
+        let body = res.into_body().collect().await.unwrap().to_bytes();
+        (status, serde_json::from_slice(&body).ok())
+    }
+
+    fn registration(ip: &str, port: u16) -> Value {
+        json!({
+            "ip": ip,
+            "port": port,
+            "revision": "abc",
+            "tags": {"az": "ap-northeast-1a", "region": "ap-northeast-1", "instance_id": "i-1", "canary": false},
+        })
+    }
+
+    fn discovery_request(resource_names: &[&str]) -> Value {
+        json!({
+            "node": {"id": "envoy-1", "cluster": "app"},
+            "resource_names": resource_names,
+        })
+    }
+
+    #[tokio::test]
+    async fn check_health() {
+        let s = MemoryStorage::new(60);
+        let req = Request::builder().uri("/hc").body(Bytes::new()).unwrap();
+        let res = handle(&s, &build_config(), req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = res.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, "ok");
+    }
+
+    #[tokio::test]
+    async fn register_get_and_delete_hosts() {
+        let s = MemoryStorage::new(60);
+        let (status, _) = request(
+            &s,
+            Method::POST,
+            "/v1/registration/user",
+            registration("10.0.0.1", 8080),
+        )
+        .await;
+        assert_eq!(status, StatusCode::ACCEPTED);
+
+        let (status, body) = request(&s, Method::GET, "/v1/registration/user", Value::Null).await;
+        assert_eq!(status, StatusCode::OK);
+        let body = body.unwrap();
+        assert_eq!(body["service"], "user");
+        assert_eq!(body["env"], "production");
+        assert_eq!(body["hosts"].as_array().unwrap().len(), 1);
+        assert_eq!(body["hosts"][0]["ip_address"], "10.0.0.1");
+        assert_eq!(body["hosts"][0]["port"], 8080);
+
+        let (status, _) = request(
+            &s,
+            Method::DELETE,
+            "/v1/registration/user/10.0.0.1:8080",
+            Value::Null,
+        )
+        .await;
+        assert_eq!(status, StatusCode::ACCEPTED);
+
+        let (_, body) = request(&s, Method::GET, "/v1/registration/user", Value::Null).await;
+        assert_eq!(body.unwrap()["hosts"], json!([]));
+
+        let (status, body) = request(
+            &s,
+            Method::DELETE,
+            "/v1/registration/user/10.0.0.1:8080",
+            Value::Null,
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body.unwrap()["id"], "HostNotFound");
+    }
+
+    #[tokio::test]
+    async fn register_hosts_of_ipv6() {
+        let s = MemoryStorage::new(60);
+        let (status, _) = request(
+            &s,
+            Method::POST,
+            "/v1/registration/user",
+            registration("::1", 8080),
+        )
+        .await;
+        assert_eq!(status, StatusCode::ACCEPTED);
 
-fn wrap_future(res: Response<Body>) -> BoxFut {
-    Box::new(future::ok(res))
+        let (status, _) = request(
+            &s,
+            Method::DELETE,
+            "/v1/registration/user/%5B::1%5D:8080",
+            Value::Null,
+        )
+        .await;
+        assert_eq!(status, StatusCode::ACCEPTED);
+    }
+
+    #[tokio::test]
+    async fn reject_invalid_registration() {
+        let s = MemoryStorage::new(60);
+        let (status, body) = request(
+            &s,
+            Method::POST,
+            "/v1/registration/user",
+            registration("not-an-ip", 0),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body.unwrap()["id"], "InvalidRegistration");
+
+        let (_, body) = request(&s, Method::GET, "/v1/registration/user", Value::Null).await;
+        assert_eq!(body.unwrap()["hosts"], json!([]));
+    }
+
+    #[tokio::test]
+    async fn reject_invalid_service_name() {
+        let s = MemoryStorage::new(60);
+        request(
+            &s,
+            Method::POST,
+            "/v1/registration/user?env=staging",
+            registration("10.0.0.1", 8080),
+        )
+        .await;
+
+        let paths = [
+            (Method::GET, "/v1/registration/user@staging"),
+            (
+                Method::DELETE,
+                "/v1/registration/user@staging/10.0.0.1:8080",
+            ),
+            (
+                Method::PUT,
+                "/v1/registration/user@staging/10.0.0.1:8080/heartbeat",
+            ),
+            (Method::GET, "/v1/revisions/user@staging"),
+        ];
+        for (method, path) in paths.iter() {
+            let (status, body) = request(&s, method.clone(), path, Value::Null).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{} {}", method, path);
+            assert_eq!(body.unwrap()["id"], "InvalidRequest");
+        }
+
+        let (_, body) = request(
+            &s,
+            Method::GET,
+            "/v1/registration/user?env=staging",
+            Value::Null,
+        )
+        .await;
+        assert_eq!(body.unwrap()["hosts"].as_array().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn get_registration_xds() {
+        let s = MemoryStorage::new(60);
+        request(
+            &s,
+            Method::POST,
+            "/v1/registration/user",
+            registration("10.0.0.1", 8080),
+        )
+        .await;
+
+        let (status, body) = request(
+            &s,
+            Method::POST,
+            "/v2/discovery:endpoints",
+            discovery_request(&["user", "unknown"]),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let body = body.unwrap();
+        assert_eq!(body["type_url"], v2xds::EDS_TYPE_URL);
+        let resources = body["resources"].as_array().unwrap();
+        assert_eq!(resources.len(), 2);
+        assert_eq!(resources[0]["cluster_name"], "user");
+        let lb_endpoints = &resources[0]["endpoints"][0]["lb_endpoints"];
+        assert_eq!(
+            lb_endpoints[0]["endpoint"]["address"]["socket_address"],
+            json!({"address": "10.0.0.1", "port_value": 8080})
+        );
+        assert_eq!(resources[1]["cluster_name"], "unknown");
+        assert_eq!(resources[1]["endpoints"], json!([]));
+
+        // The same version is not sent again.
+        let mut d_req = discovery_request(&["user", "unknown"]);
+        d_req["version_info"] = body["version_info"].clone();
+        let (status, _) = request(&s, Method::POST, "/v2/discovery:endpoints", d_req).await;
+        assert_eq!(status, StatusCode::NOT_MODIFIED);
+    }
+
+    #[tokio::test]
+    async fn get_registration_xds_in_namespace() {
+        let s = MemoryStorage::new(60);
+        request(
+            &s,
+            Method::POST,
+            "/ns/team-a/v1/registration/user",
+            registration("10.0.0.1", 8080),
+        )
+        .await;
+        request(
+            &s,
+            Method::POST,
+            "/v1/registration/user",
+            registration("10.0.0.2", 8080),
+        )
+        .await;
+
+        let address_of = |body: &Value, i: usize| {
+            body["resources"][i]["endpoints"][0]["lb_endpoints"][0]["endpoint"]["address"]
+                ["socket_address"]["address"]
+                .clone()
+        };
+        let (_, body) = request(
+            &s,
+            Method::POST,
+            "/ns/team-a/v2/discovery:endpoints",
+            discovery_request(&["user", "team-a/user"]),
+        )
+        .await;
+        let body = body.unwrap();
+        assert_eq!(body["resources"][0]["cluster_name"], "user");
+        assert_eq!(address_of(&body, 0), "10.0.0.1");
+        assert_eq!(body["resources"][1]["cluster_name"], "team-a/user");
+        assert_eq!(address_of(&body, 1), "10.0.0.1");
+
+        let (_, body) = request(
+            &s,
+            Method::POST,
+            "/v2/discovery:endpoints",
+            discovery_request(&["user"]),
+        )
+        .await;
+        assert_eq!(address_of(&body.unwrap(), 0), "10.0.0.2");
+
+        // Prefixed requests only reach their own namespace.
+        let (status, body) = request(
+            &s,
+            Method::POST,
+            "/ns/team-b/v2/discovery:endpoints",
+            discovery_request(&["team-a/user"]),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body.unwrap()["id"], "InvalidRequest");
+    }
+
+    #[tokio::test]
+    async fn reject_unsupported_type_url() {
+        let s = MemoryStorage::new(60);
+        let mut d_req = discovery_request(&["user"]);
+        d_req["type_url"] = json!(v2xds::CDS_TYPE_URL);
+        let (status, _) = request(&s, Method::POST, "/v2/discovery:endpoints", d_req).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn cluster_key_of_namespaced_cluster() {
+        let c = build_config();
+        assert_eq!(cluster_key(&c, "user").unwrap(), "user");
+        assert_eq!(cluster_key(&c, "team/user").unwrap(), "team/user");
+        for name in [
+            "user@staging",
+            "team/sub/user",
+            "/user",
+            "team/",
+            "",
+            "te.am/user",
+        ] {
+            assert_eq!(cluster_key(&c, name), None, "{}", name);
+        }
+
+        let c = Config {
+            env: "staging".to_owned(),
+            ..c
+        };
+        assert_eq!(cluster_key(&c, "team/user").unwrap(), "team/user@staging");
+    }
+
+    #[tokio::test]
+    async fn reject_invalid_cluster_names() {
+        let s = MemoryStorage::new(60);
+        request(
+            &s,
+            Method::POST,
+            "/v1/registration/user?env=staging",
+            registration("10.0.0.1", 8080),
+        )
+        .await;
+        for name in ["user@staging", "team/sub/user", ""] {
+            let d_req = discovery_request(&[name]);
+            let (status, body) = request(&s, Method::POST, "/v2/discovery:endpoints", d_req).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{}", name);
+            assert_eq!(body.unwrap()["id"], "InvalidRequest");
+        }
+    }
 }
@@ -0,0 +1,564 @@
+use std::sync::{Arc, Mutex};
+
+use log::{info, warn};
+use rusqlite::{params, Connection, ErrorCode, OptionalExtension, Row};
+
+use super::metrics;
+use super::quarantine::{self, QuarantinedItem};
+use super::storage::{
+    build_data_error, fetch_epoch_now, join_ip_port, split_ip_port, ErrorKind, StorageError,
+};
+use super::types::{HealthStatus, Host, RevisionWeights, ServiceSummary, Storage, StorageFuture};
+
+const CREATE_TABLE_SQL: &str = "CREATE TABLE IF NOT EXISTS hosts (
+    service TEXT NOT NULL,
+    ip_port TEXT NOT NULL,
+    last_check_in TEXT NOT NULL,
+    expire_time INTEGER NOT NULL,
+    revision TEXT NOT NULL,
+    tags TEXT NOT NULL,
+    health_status TEXT NOT NULL DEFAULT 'HEALTHY',
+    PRIMARY KEY (service, ip_port)
+)";
+
+// Rollout weights are kept in their own table, since they have no ip_port nor expire_time.
+const CREATE_REVISION_WEIGHTS_TABLE_SQL: &str = "CREATE TABLE IF NOT EXISTS revision_weights (
+    service TEXT NOT NULL PRIMARY KEY,
+    weights TEXT NOT NULL
+)";
+
+// Persists hosts in a local SQLite file, using the same `service` + `ip_port` primary key and
+// `expire_time` semantics as the DynamoDB table.
+#[derive(Clone)]
+pub struct SqliteStorage {
+    pub ttl: u64,
+    // Skip corrupt rows and put them into quarantine instead of failing the whole query.
+    pub skip_corrupt_items: bool,
+    conn: Arc<Mutex<Connection>>,
+}
+
+// Raw columns of a row in the hosts table.
+struct SqliteHost {
+    ip_port: String,
+    last_check_in: String,
+    expire_time: i64,
+    revision: String,
+    tags: String,
+    health_status: String,
+}
+
+impl SqliteStorage {
+    pub fn open(
+        path: &str,
+        ttl: u64,
+        skip_corrupt_items: bool,
+    ) -> Result<SqliteStorage, StorageError> {
+        let conn = Connection::open(path).map_err(|e| build_api_error("open", e))?;
+        conn.execute(CREATE_TABLE_SQL, params![])
+            .map_err(|e| build_api_error("create table", e))?;
+        conn.execute(CREATE_REVISION_WEIGHTS_TABLE_SQL, params![])
+            .map_err(|e| build_api_error("create table", e))?;
+        migrate(&conn).map_err(|e| build_api_error("migrate", e))?;
+        info!("open(): succeed to open SQLite database: path={}", path);
+        Ok(SqliteStorage {
+            ttl,
+            skip_corrupt_items,
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    fn query_hosts(&self, name: &str) -> Result<Vec<Host>, StorageError> {
+        let epoch_now = fetch_epoch_now()?;
+        let conn = self.conn.lock().map_err(|_| build_lock_error())?;
+        let mut stmt = conn
+            .prepare_cached(
+                "SELECT ip_port, last_check_in, expire_time, revision, tags, health_status \
+                 FROM hosts WHERE service = ?1",
+            )
+            .map_err(|e| build_api_error("query", e))?;
+        let rows = stmt
+            .query_map(params![name], convert_row_to_sqlite_host)
+            .map_err(|e| build_api_error("query", e))?;
+
+        let mut hosts = Vec::new();
+        let mut expired_hosts_size = 0;
+        let mut quarantined = Vec::new();
+        for row in rows {
+            let row = row.map_err(|e| build_api_error("query", e))?;
+            let ip_port = row.ip_port.to_owned();
+            let host = match convert_sqlite_host_to_domain_host(name, row) {
+                Ok(v) => v,
+                Err(e) if self.skip_corrupt_items => {
+                    warn!(
+                        "Skip corrupt item: service={}, ip_port={}, key={:?}, error={}",
+                        name, ip_port, e.key, e
+                    );
+                    quarantined.push(QuarantinedItem::new(name, Some(ip_port), &e));
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            if host.expire_time >= epoch_now {
+                hosts.push(host);
+            } else {
+                info!(
+                    "Expired host found: service={}, ip={}, port={}, expire_time={}, now={}",
+                    name, host.ip_address, host.port, host.expire_time, epoch_now
+                );
+                expired_hosts_size += 1;
+            }
+        }
+        metrics::set_hosts(name, hosts.len(), expired_hosts_size);
+        if self.skip_corrupt_items {
+            quarantine::set(name, quarantined);
+        }
+        info!(
+            "query_items(): succeed to return hosts: service={}, hosts-size={}",
+            name,
+            hosts.len()
+        );
+        Ok(hosts)
+    }
+
+    fn store_host(&self, name: &str, host: Host) -> Result<(), StorageError> {
+        let epoch_now = fetch_epoch_now()?;
+        let ip = host.ip_address.to_owned();
+        let port = host.port;
+        let tags = serde_json::to_string(&host.tags).map_err(|e| {
+            build_data_error("tags", format!("Failed to serialize tags into JSON: {}", e))
+        })?;
+
+        let conn = self.conn.lock().map_err(|_| build_lock_error())?;
+        conn.execute(
+            // Keep health_status of a live host, but reset the one of an expired host.
+            "INSERT INTO hosts \
+             (service, ip_port, last_check_in, expire_time, revision, tags, health_status) \
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7) \
+             ON CONFLICT (service, ip_port) DO UPDATE SET \
+             last_check_in = excluded.last_check_in, \
+             expire_time = excluded.expire_time, \
+             revision = excluded.revision, \
+             tags = excluded.tags, \
+             health_status = CASE WHEN hosts.expire_time < ?8 \
+             THEN excluded.health_status ELSE hosts.health_status END",
+            params![
+                name,
+                join_ip_port(&host.ip_address, u64::from(host.port)),
+                host.last_check_in,
+                host.expire_time as i64,
+                host.revision,
+                tags,
+                host.health_status.as_str(),
+                epoch_now as i64,
+            ],
+        )
+        .map_err(|e| build_api_error("insert", e))?;
+        // There is no TTL sweeper like DynamoDB's one, so purge expired rows on writes.
+        conn.execute(
+            "DELETE FROM hosts WHERE service = ?1 AND expire_time < ?2",
+            params![name, epoch_now as i64],
+        )
+        .map_err(|e| build_api_error("delete", e))?;
+        info!(
+            "store_item(): succeed to store item: service={}, ip={}, port={}",
+            name, ip, port
+        );
+        Ok(())
+    }
+
+    fn delete_host(&self, name: &str, ip: String, port: u64) -> Result<Option<Host>, StorageError> {
+        let epoch_now = fetch_epoch_now()?;
+        let ip_port = join_ip_port(&ip, port);
+
+        let conn = self.conn.lock().map_err(|_| build_lock_error())?;
+        let row = conn
+            .query_row(
+                "SELECT ip_port, last_check_in, expire_time, revision, tags, health_status \
+                 FROM hosts WHERE service = ?1 AND ip_port = ?2",
+                params![name, ip_port],
+                convert_row_to_sqlite_host,
+            )
+            .optional()
+            .map_err(|e| build_api_error("query", e))?;
+        conn.execute(
+            "DELETE FROM hosts WHERE service = ?1 AND ip_port = ?2",
+            params![name, ip_port],
+        )
+        .map_err(|e| build_api_error("delete", e))?;
+        info!(
+            "delete_item(): succeed to delete_item item: service={}, ip={}, port={}",
+            name, ip, port
+        );
+
+        match row {
+            Some(r) => {
+                let h = convert_sqlite_host_to_domain_host(name, r)?;
+                if h.expire_time >= epoch_now {
+                    Ok(Some(h))
+                } else {
+                    Ok(None)
+                }
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn refresh_host(
+        &self,
+        name: &str,
+        ip: String,
+        port: u64,
+        last_check_in: String,
+        expire_time: u64,
+    ) -> Result<Option<Host>, StorageError> {
+        let epoch_now = fetch_epoch_now()?;
+        let ip_port = join_ip_port(&ip, port);
+
+        let conn = self.conn.lock().map_err(|_| build_lock_error())?;
+        let updated = conn
+            .execute(
+                "UPDATE hosts SET expire_time = ?1, last_check_in = ?2 \
+                 WHERE service = ?3 AND ip_port = ?4 AND expire_time >= ?5",
+                params![
+                    expire_time as i64,
+                    last_check_in,
+                    name,
+                    ip_port,
+                    epoch_now as i64
+                ],
+            )
+            .map_err(|e| build_api_error("update", e))?;
+        if updated == 0 {
+            return Ok(None);
+        }
+        let row = conn
+            .query_row(
+                "SELECT ip_port, last_check_in, expire_time, revision, tags, health_status \
+                 FROM hosts WHERE service = ?1 AND ip_port = ?2",
+                params![name, ip_port],
+                convert_row_to_sqlite_host,
+            )
+            .map_err(|e| build_api_error("query", e))?;
+        info!(
+            "refresh_item(): succeed to refresh item: service={}, ip={}, port={}",
+            name, ip, port
+        );
+        Ok(Some(convert_sqlite_host_to_domain_host(name, row)?))
+    }
+
+    fn update_host_status(
+        &self,
+        name: &str,
+        ip: String,
+        port: u64,
+        status: HealthStatus,
+    ) -> Result<Option<Host>, StorageError> {
+        let epoch_now = fetch_epoch_now()?;
+        let ip_port = join_ip_port(&ip, port);
+
+        let conn = self.conn.lock().map_err(|_| build_lock_error())?;
+        let updated = conn
+            .execute(
+                "UPDATE hosts SET health_status = ?1 \
+                 WHERE service = ?2 AND ip_port = ?3 AND expire_time >= ?4",
+                params![status.as_str(), name, ip_port, epoch_now as i64],
+            )
+            .map_err(|e| build_api_error("update", e))?;
+        if updated == 0 {
+            return Ok(None);
+        }
+        let row = conn
+            .query_row(
+                "SELECT ip_port, last_check_in, expire_time, revision, tags, health_status \
+                 FROM hosts WHERE service = ?1 AND ip_port = ?2",
+                params![name, ip_port],
+                convert_row_to_sqlite_host,
+            )
+            .map_err(|e| build_api_error("query", e))?;
+        info!(
+            "update_status(): succeed to update status: service={}, ip={}, port={}, status={}",
+            name,
+            ip,
+            port,
+            status.as_str()
+        );
+        Ok(Some(convert_sqlite_host_to_domain_host(name, row)?))
+    }
+
+    fn summarize_services(&self) -> Result<Vec<ServiceSummary>, StorageError> {
+        let epoch_now = fetch_epoch_now()?;
+        let conn = self.conn.lock().map_err(|_| build_lock_error())?;
+        let mut stmt = conn
+            .prepare_cached(
+                "SELECT service, expire_time, last_check_in, revision FROM hosts ORDER BY service",
+            )
+            .map_err(|e| build_api_error("query", e))?;
+        let rows = stmt
+            .query_map(params![], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, i64>(1)?,
+                    row.get::<_, String>(2)?,
+                    row.get::<_, String>(3)?,
+                ))
+            })
+            .map_err(|e| build_api_error("query", e))?;
+
+        let mut summaries: Vec<ServiceSummary> = Vec::new();
+        // The key of the last summary. Summaries only keep the service name split from it.
+        let mut last_key: Option<String> = None;
+        for row in rows {
+            let (service, expire_time, last_check_in, revision) =
+                row.map_err(|e| build_api_error("query", e))?;
+            // Rows are ordered by service, so a new service always comes at the end.
+            if last_key.as_deref() != Some(service.as_str()) {
+                summaries.push(ServiceSummary::new(service.clone()));
+                last_key = Some(service);
+            }
+            if let Some(summary) = summaries.last_mut() {
+                summary.add_host(expire_time as u64, &last_check_in, &revision, epoch_now);
+            }
+        }
+        info!(
+            "list_services(): succeed to return services: services-size={}",
+            summaries.len()
+        );
+        Ok(summaries)
+    }
+
+    fn query_weights(&self, name: &str) -> Result<RevisionWeights, StorageError> {
+        let conn = self.conn.lock().map_err(|_| build_lock_error())?;
+        let row: Option<String> = conn
+            .query_row(
+                "SELECT weights FROM revision_weights WHERE service = ?1",
+                params![name],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(|e| build_api_error("query", e))?;
+        let weights = match row {
+            Some(w) => serde_json::from_str(&w).map_err(|e| {
+                build_data_error(
+                    "weights",
+                    format!(
+                        "\"weights\" of \"{}\" is expected to be a valid JSON but is not: {}",
+                        name, e
+                    ),
+                )
+            })?,
+            None => RevisionWeights::new(),
+        };
+        info!(
+            "query_revision_weights(): succeed to return weights: service={}, weights={:?}",
+            name, weights
+        );
+        Ok(weights)
+    }
+
+    fn store_weights(&self, name: &str, weights: RevisionWeights) -> Result<(), StorageError> {
+        let conn = self.conn.lock().map_err(|_| build_lock_error())?;
+        if weights.is_empty() {
+            conn.execute(
+                "DELETE FROM revision_weights WHERE service = ?1",
+                params![name],
+            )
+            .map_err(|e| build_api_error("delete", e))?;
+        } else {
+            let json = serde_json::to_string(&weights).map_err(|e| {
+                build_data_error(
+                    "weights",
+                    format!("Failed to serialize weights into JSON: {}", e),
+                )
+            })?;
+            conn.execute(
+                "INSERT INTO revision_weights (service, weights) VALUES (?1, ?2) \
+                 ON CONFLICT (service) DO UPDATE SET weights = excluded.weights",
+                params![name, json],
+            )
+            .map_err(|e| build_api_error("insert", e))?;
+        }
+        info!(
+            "store_revision_weights(): succeed to store weights: service={}, weights={:?}",
+            name, weights
+        );
+        Ok(())
+    }
+}
+
+// SQLite calls block the current thread, so they run on tokio's blocking thread pool instead of
+// the runtime's workers.
+impl Storage for SqliteStorage {
+    type E = StorageError;
+
+    fn query_items(&self, name: &str) -> StorageFuture<Vec<Host>, Self::E> {
+        let st = self.clone();
+        let name = name.to_owned();
+        run_blocking(move || st.query_hosts(&name))
+    }
+
+    fn store_item(&self, name: &str, host: Host) -> StorageFuture<(), Self::E> {
+        let st = self.clone();
+        let name = name.to_owned();
+        run_blocking(move || st.store_host(&name, host))
+    }
+
+    fn delete_item(
+        &self,
+        name: &str,
+        ip: String,
+        port: u64,
+    ) -> StorageFuture<Option<Host>, Self::E> {
+        let st = self.clone();
+        let name = name.to_owned();
+        run_blocking(move || st.delete_host(&name, ip, port))
+    }
+
+    fn refresh_item(
+        &self,
+        name: &str,
+        ip: String,
+        port: u64,
+        last_check_in: String,
+        expire_time: u64,
+    ) -> StorageFuture<Option<Host>, Self::E> {
+        let st = self.clone();
+        let name = name.to_owned();
+        run_blocking(move || st.refresh_host(&name, ip, port, last_check_in, expire_time))
+    }
+
+    fn update_status(
+        &self,
+        name: &str,
+        ip: String,
+        port: u64,
+        status: HealthStatus,
+    ) -> StorageFuture<Option<Host>, Self::E> {
+        let st = self.clone();
+        let name = name.to_owned();
+        run_blocking(move || st.update_host_status(&name, ip, port, status))
+    }
+
+    fn list_services(&self) -> StorageFuture<Vec<ServiceSummary>, Self::E> {
+        let st = self.clone();
+        run_blocking(move || st.summarize_services())
+    }
+
+    fn query_revision_weights(&self, name: &str) -> StorageFuture<RevisionWeights, Self::E> {
+        let st = self.clone();
+        let name = name.to_owned();
+        run_blocking(move || st.query_weights(&name))
+    }
+
+    fn store_revision_weights(
+        &self,
+        name: &str,
+        weights: RevisionWeights,
+    ) -> StorageFuture<(), Self::E> {
+        let st = self.clone();
+        let name = name.to_owned();
+        run_blocking(move || st.store_weights(&name, weights))
+    }
+
+    fn ttl(&self) -> u64 {
+        self.ttl
+    }
+}
+
+fn run_blocking<T, F>(f: F) -> StorageFuture<T, StorageError>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T, StorageError> + Send + 'static,
+{
+    Box::pin(async move {
+        tokio::task::spawn_blocking(f).await.map_err(|e| {
+            StorageError::new(
+                ErrorKind::System,
+                format!("Failed to run a blocking SQLite call: {}", e),
+            )
+        })?
+    })
+}
+
+fn convert_row_to_sqlite_host(row: &Row) -> rusqlite::Result<SqliteHost> {
+    Ok(SqliteHost {
+        ip_port: row.get(0)?,
+        last_check_in: row.get(1)?,
+        expire_time: row.get(2)?,
+        revision: row.get(3)?,
+        tags: row.get(4)?,
+        health_status: row.get(5)?,
+    })
+}
+
+fn convert_sqlite_host_to_domain_host(name: &str, h: SqliteHost) -> Result<Host, StorageError> {
+    let tags = serde_json::from_str(&h.tags).map_err(|e| {
+        build_data_error(
+            "tags",
+            format!(
+                "\"tags\" of \"{}\" is expected to be a valid JSON but is not: {}",
+                h.ip_port, e
+            ),
+        )
+    })?;
+    if h.expire_time < 0 {
+        return Err(build_data_error(
+            "expire_time",
+            format!(
+                "\"expire_time\" of \"{}\" must not be negative: {}",
+                h.ip_port, h.expire_time
+            ),
+        ));
+    }
+    let health_status = HealthStatus::parse(&h.health_status).ok_or_else(|| {
+        build_data_error(
+            "health_status",
+            format!(
+                "\"health_status\" of \"{}\" is invalid: {}",
+                h.ip_port, h.health_status
+            ),
+        )
+    })?;
+    let (ip_address, port) = split_ip_port(&h.ip_port)?;
+    Ok(Host {
+        ip_address,
+        port,
+        last_check_in: h.last_check_in,
+        expire_time: h.expire_time as u64,
+        revision: h.revision,
+        service: name.to_owned(),
+        tags,
+        health_status,
+    })
+}
+
+// Databases created before health_status was introduced lack the column.
+fn migrate(conn: &Connection) -> rusqlite::Result<()> {
+    let has_health_status: i64 = conn.query_row(
+        "SELECT COUNT(*) FROM pragma_table_info('hosts') WHERE name = 'health_status'",
+        params![],
+        |row| row.get(0),
+    )?;
+    if has_health_status == 0 {
+        conn.execute(
+            "ALTER TABLE hosts ADD COLUMN health_status TEXT NOT NULL DEFAULT 'HEALTHY'",
+            params![],
+        )?;
+    }
+    Ok(())
+}
+
+fn build_api_error(op: &str, e: rusqlite::Error) -> StorageError {
+    // Another connection holds the lock of the database file for longer than the busy timeout.
+    let kind = match e.sqlite_error_code() {
+        Some(ErrorCode::DatabaseBusy) | Some(ErrorCode::DatabaseLocked) => ErrorKind::Throttled,
+        _ => ErrorKind::Api,
+    };
+    StorageError::new(kind, format!("SQLite Error in {}: {}", op, e))
+}
+
+fn build_lock_error() -> StorageError {
+    StorageError::new(
+        ErrorKind::System,
+        "SQLite connection lock is poisoned".to_owned(),
+    )
+}
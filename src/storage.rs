@@ -1,24 +1,66 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::error;
 use std::fmt;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::net::IpAddr;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
-use log::info;
-use rusoto_dynamodb::{AttributeValue, DeleteItemInput, PutItemInput, QueryInput};
+use aws_sdk_dynamodb::error::{DisplayErrorContext, ProvideErrorMetadata, SdkError};
+use aws_sdk_dynamodb::types::{AttributeValue, ReturnValue};
+use aws_sdk_dynamodb::Client;
+use futures::Future;
+use log::{info, warn};
 
-use super::types::{Host, Storage, Tag};
+use super::metrics;
+use super::quarantine::{self, QuarantinedItem};
+use super::types::{
+    HealthStatus, Host, LabelValue, RevisionWeights, ServiceSummary, Storage, StorageFuture, Tag,
+};
+
+// The sort key of the item holding revision weights in the partition of a service. It never
+// collides with hosts' keys, which always end with ":port".
+const REVISION_WEIGHTS_IP_PORT: &str = "revision_weights";
 
 #[derive(Debug, Clone)]
-enum ErrorKind {
+pub(crate) enum ErrorKind {
     Api,
+    // The backend rejected the call because of its capacity. Worth retrying later.
+    Throttled,
+    // The backend did not respond in time. Worth retrying later.
+    Timeout,
     Data,
     System,
 }
 
 #[derive(Debug, Clone)]
 pub struct StorageError {
-    kind: ErrorKind,
-    msg: String,
+    pub(crate) kind: ErrorKind,
+    pub(crate) msg: String,
+    // The attribute of the stored record which is missing or malformed, for Data errors.
+    pub(crate) key: Option<String>,
+}
+
+impl ErrorKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            ErrorKind::Api => "api",
+            ErrorKind::Throttled => "throttled",
+            ErrorKind::Timeout => "timeout",
+            ErrorKind::Data => "data",
+            ErrorKind::System => "system",
+        }
+    }
+}
+
+impl StorageError {
+    // Every error is counted in metrics when it is built.
+    pub(crate) fn new(kind: ErrorKind, msg: String) -> StorageError {
+        metrics::inc_storage_errors(kind.as_str());
+        StorageError {
+            kind,
+            msg,
+            key: None,
+        }
+    }
 }
 
 impl fmt::Display for StorageError {
@@ -35,56 +77,55 @@ impl error::Error for StorageError {
 }
 
 #[derive(Clone)]
-pub struct StorageImpl<DynamoDb> {
+pub struct StorageImpl {
     pub table_name: String,
     pub ttl: u64,
-    pub dynamodb_client: DynamoDb,
-    pub timeout: std::time::Duration,
+    // The operation timeout is configured on the client.
+    pub dynamodb_client: Client,
+    // Skip corrupt items and put them into quarantine instead of failing the whole query.
+    pub skip_corrupt_items: bool,
 }
 
-impl<DynamoDb> Storage for StorageImpl<DynamoDb>
-where
-    DynamoDb: rusoto_dynamodb::DynamoDb + Send + Sync + Clone + 'static,
-{
-    type E = StorageError;
-
-    fn query_items(&self, name: &str) -> Result<Vec<Host>, Self::E> {
+impl StorageImpl {
+    async fn query_hosts(&self, name: &str) -> Result<Vec<Host>, StorageError> {
+        let epoch_now = fetch_epoch_now()?;
         let mut hosts = Vec::new();
-        let mut last_evaluated_key: Option<HashMap<String, AttributeValue>> = None;
-        let table_name = self.table_name.to_owned();
-        let now = match SystemTime::now().duration_since(UNIX_EPOCH) {
-            Ok(v) => v,
-            Err(_) => {
-                return Err(StorageError {
-                    kind: ErrorKind::System,
-                    msg: "Cloud not fetch system time".to_owned(),
-                })
-            }
-        };
-        let epoch_now = now.as_secs();
+        let mut expired_hosts_size = 0;
+        let mut quarantined = Vec::new();
 
+        // Follow last_evaluated_key until every page is fetched.
+        let mut last_evaluated_key = None;
         loop {
-            let tn = table_name.to_owned();
-            let mut query_input = build_query_input(tn, &name);
-            query_input.exclusive_start_key = last_evaluated_key;
-            let res = match self
-                .dynamodb_client
-                .query(query_input)
-                .with_timeout(self.timeout)
-                .sync()
-            {
-                Ok(res) => res,
-                Err(e) => {
-                    return Err(StorageError {
-                        kind: ErrorKind::Api,
-                        msg: format!("API Error in query: {}", e.to_string()),
-                    })
+            let res = observe_dynamodb_request(
+                "query",
+                self.dynamodb_client
+                    .query()
+                    .table_name(&self.table_name)
+                    .key_condition_expression("service = :service_val")
+                    .expression_attribute_values(":service_val", build_string_attr(name.to_owned()))
+                    .set_exclusive_start_key(last_evaluated_key)
+                    .send(),
+            )
+            .await
+            .map_err(|e| build_api_error("query", e))?;
+
+            for h in res.items.unwrap_or_default() {
+                let ip_port = h.get("ip_port").and_then(|v| v.as_s().ok()).cloned();
+                if ip_port.as_deref() == Some(REVISION_WEIGHTS_IP_PORT) {
+                    continue;
                 }
-            };
-            last_evaluated_key = res.last_evaluated_key;
-            let items = res.items.expect("items of query result is missing");
-            for h in items {
-                let host = convert_ddb_host_to_domain_host(&name, h)?;
+                let host = match convert_ddb_host_to_domain_host(name, h) {
+                    Ok(v) => v,
+                    Err(e) if self.skip_corrupt_items => {
+                        warn!(
+                            "Skip corrupt item: service={}, ip_port={:?}, key={:?}, error={}",
+                            name, ip_port, e.key, e
+                        );
+                        quarantined.push(QuarantinedItem::new(name, ip_port, &e));
+                        continue;
+                    }
+                    Err(e) => return Err(e),
+                };
                 if host.expire_time >= epoch_now {
                     hosts.push(host);
                 } else {
@@ -92,12 +133,19 @@ where
                         "Expired host found: service={}, ip={}, port={}, expire_time={}, now={}",
                         name, host.ip_address, host.port, host.expire_time, epoch_now
                     );
+                    expired_hosts_size += 1;
                 }
             }
+            last_evaluated_key = res.last_evaluated_key;
             if last_evaluated_key.is_none() {
                 break;
             }
         }
+
+        metrics::set_hosts(name, hosts.len(), expired_hosts_size);
+        if self.skip_corrupt_items {
+            quarantine::set(name, quarantined);
+        }
         info!(
             "query_items(): succeed to return hosts: service={}, hosts-size={}",
             name,
@@ -106,70 +154,404 @@ where
         Ok(hosts)
     }
 
-    fn store_item(&self, name: &str, host: Host) -> Result<(), Self::E> {
-        let table_name = self.table_name.to_owned();
+    // Updates the item of a live host in place, so that its health_status is kept. New or expired
+    // hosts are put from scratch.
+    async fn store_host(&self, name: &str, host: Host) -> Result<(), StorageError> {
         let ip = host.ip_address.to_owned();
         let port = host.port;
 
-        if let Err(e) = self
-            .dynamodb_client
-            .put_item(build_put_item_input(table_name, &name, host))
-            .with_timeout(self.timeout)
-            .sync()
-        {
-            Err(StorageError {
-                kind: ErrorKind::Api,
-                msg: format!("API Error in put_item: {}", e.to_string()),
-            })
-        } else {
-            info!(
-                "store_item(): succeed to store item: service={}, ip={}, port={}",
+        let mut values = HashMap::new();
+        values.insert(
+            ":last_check_in".to_owned(),
+            build_string_attr(host.last_check_in.to_owned()),
+        );
+        values.insert(
+            ":expire_time".to_owned(),
+            build_number_attr(host.expire_time),
+        );
+        values.insert(
+            ":revision".to_owned(),
+            build_string_attr(host.revision.to_owned()),
+        );
+        values.insert(
+            ":tags".to_owned(),
+            AttributeValue::M(convert_domain_tag_to_ddb_tag(host.tags.clone())),
+        );
+        let updated = self
+            .update_live_item(
+                name,
+                &ip,
+                u64::from(port),
+                "SET last_check_in = :last_check_in, expire_time = :expire_time, \
+                 revision = :revision, tags = :tags",
+                values,
+            )
+            .await?;
+
+        if updated.is_none() {
+            observe_dynamodb_request(
+                "put_item",
+                self.dynamodb_client
+                    .put_item()
+                    .table_name(&self.table_name)
+                    .set_item(Some(convert_domain_host_to_ddb_host(name, host)))
+                    .send(),
+            )
+            .await
+            .map_err(|e| build_api_error("put_item", e))?;
+        }
+        info!(
+            "store_item(): succeed to store item: service={}, ip={}, port={}",
+            name, ip, port
+        );
+        Ok(())
+    }
+
+    async fn delete_host(
+        &self,
+        name: &str,
+        ip: String,
+        port: u64,
+    ) -> Result<Option<Host>, StorageError> {
+        let out = observe_dynamodb_request(
+            "delete_item",
+            self.dynamodb_client
+                .delete_item()
+                .table_name(&self.table_name)
+                .set_key(Some(build_primary_key(name, &ip, port)))
+                .return_values(ReturnValue::AllOld)
+                .send(),
+        )
+        .await
+        .map_err(|e| build_api_error("delete_item", e))?;
+        info!(
+            "delete_item(): succeed to delete_item item: service={}, ip={}, port={}",
+            name, ip, port
+        );
+
+        match out.attributes {
+            Some(m) => {
+                let h = convert_ddb_host_to_domain_host(name, m)?;
+                if h.expire_time >= fetch_epoch_now()? {
+                    Ok(Some(h))
+                } else {
+                    Ok(None)
+                }
+            }
+            None => Ok(None),
+        }
+    }
+
+    async fn refresh_host(
+        &self,
+        name: &str,
+        ip: String,
+        port: u64,
+        last_check_in: String,
+        expire_time: u64,
+    ) -> Result<Option<Host>, StorageError> {
+        let mut values = HashMap::new();
+        values.insert(":expire_time".to_owned(), build_number_attr(expire_time));
+        values.insert(
+            ":last_check_in".to_owned(),
+            build_string_attr(last_check_in),
+        );
+        let host = self
+            .update_live_item(
+                name,
+                &ip,
+                port,
+                "SET expire_time = :expire_time, last_check_in = :last_check_in",
+                values,
+            )
+            .await?;
+        match host {
+            Some(_) => info!(
+                "refresh_item(): succeed to refresh item: service={}, ip={}, port={}",
+                name, ip, port
+            ),
+            None => info!(
+                "refresh_item(): host not found or expired: service={}, ip={}, port={}",
                 name, ip, port
-            );
-            Ok(())
+            ),
+        }
+        Ok(host)
+    }
+
+    async fn update_host_status(
+        &self,
+        name: &str,
+        ip: String,
+        port: u64,
+        status: HealthStatus,
+    ) -> Result<Option<Host>, StorageError> {
+        let mut values = HashMap::new();
+        values.insert(
+            ":health_status".to_owned(),
+            build_string_attr(status.as_str().to_owned()),
+        );
+        let host = self
+            .update_live_item(
+                name,
+                &ip,
+                port,
+                "SET health_status = :health_status",
+                values,
+            )
+            .await?;
+        match host {
+            Some(_) => info!(
+                "update_status(): succeed to update status: service={}, ip={}, port={}, status={}",
+                name,
+                ip,
+                port,
+                status.as_str()
+            ),
+            None => info!(
+                "update_status(): host not found or expired: service={}, ip={}, port={}",
+                name, ip, port
+            ),
+        }
+        Ok(host)
+    }
+
+    // Only updates a live item, so that a heartbeat never resurrects a deregistered host.
+    // Returns None when the host does not exist or is already expired.
+    async fn update_live_item(
+        &self,
+        name: &str,
+        ip: &str,
+        port: u64,
+        update_expression: &str,
+        mut values: HashMap<String, AttributeValue>,
+    ) -> Result<Option<Host>, StorageError> {
+        let epoch_now = fetch_epoch_now()?;
+        values.insert(":now".to_owned(), build_number_attr(epoch_now));
+
+        let res = observe_dynamodb_request(
+            "update_item",
+            self.dynamodb_client
+                .update_item()
+                .table_name(&self.table_name)
+                .set_key(Some(build_primary_key(name, ip, port)))
+                .update_expression(update_expression)
+                .condition_expression("attribute_exists(ip_port) AND expire_time >= :now")
+                .set_expression_attribute_values(Some(values))
+                .return_values(ReturnValue::AllNew)
+                .send(),
+        )
+        .await;
+        match res {
+            Ok(out) => match out.attributes {
+                Some(m) => Ok(Some(convert_ddb_host_to_domain_host(name, m)?)),
+                None => Ok(None),
+            },
+            Err(e)
+                if e.as_service_error()
+                    .is_some_and(|e| e.is_conditional_check_failed_exception()) =>
+            {
+                Ok(None)
+            }
+            Err(e) => Err(build_api_error("update_item", e)),
         }
     }
 
-    fn delete_item(&self, name: &str, ip: String, port: u64) -> Result<Option<Host>, Self::E> {
-        let table_name = self.table_name.to_owned();
-
-        match self
-            .dynamodb_client
-            .delete_item(build_delete_item_input(table_name, name, &ip, port))
-            .with_timeout(self.timeout)
-            .sync()
-        {
-            Ok(out) => {
-                info!(
-                    "delete_item(): succeed to delete_item item: service={}, ip={}, port={}",
-                    name, ip, port
-                );
-                match out.attributes {
-                    Some(m) => {
-                        let h = convert_ddb_host_to_domain_host(name, m)?;
-                        let now = match SystemTime::now().duration_since(UNIX_EPOCH) {
-                            Ok(v) => v,
-                            Err(_) => {
-                                return Err(StorageError {
-                                    kind: ErrorKind::System,
-                                    msg: "Cloud not fetch system time".to_owned(),
-                                })
-                            }
-                        };
-                        if h.expire_time >= now.as_secs() {
-                            Ok(Some(h))
-                        } else {
-                            Ok(None)
-                        }
+    async fn summarize_services(&self) -> Result<Vec<ServiceSummary>, StorageError> {
+        let epoch_now = fetch_epoch_now()?;
+        let mut services: BTreeMap<String, ServiceSummary> = BTreeMap::new();
+
+        let mut last_evaluated_key = None;
+        loop {
+            let res = observe_dynamodb_request(
+                "scan",
+                self.dynamodb_client
+                    .scan()
+                    .table_name(&self.table_name)
+                    .projection_expression("service, ip_port, expire_time, last_check_in, revision")
+                    .set_exclusive_start_key(last_evaluated_key)
+                    .send(),
+            )
+            .await
+            .map_err(|e| build_api_error("scan", e))?;
+
+            for mut item in res.items.unwrap_or_default() {
+                if let Some(AttributeValue::S(ip_port)) = item.get("ip_port") {
+                    if ip_port == REVISION_WEIGHTS_IP_PORT {
+                        continue;
                     }
-                    None => Ok(None),
                 }
+                let service = extract_string(&mut item, "service")?;
+                let (expire_time, last_check_in, revision) = match extract_summary_attrs(&mut item)
+                {
+                    Ok(v) => v,
+                    // Quarantined by the next query of the service.
+                    Err(e) if self.skip_corrupt_items => {
+                        warn!(
+                            "Skip corrupt item: service={}, key={:?}, error={}",
+                            service, e.key, e
+                        );
+                        continue;
+                    }
+                    Err(e) => return Err(e),
+                };
+                services
+                    .entry(service.to_owned())
+                    .or_insert_with(|| ServiceSummary::new(service))
+                    .add_host(expire_time, &last_check_in, &revision, epoch_now);
+            }
+            last_evaluated_key = res.last_evaluated_key;
+            if last_evaluated_key.is_none() {
+                break;
             }
-            Err(e) => Err(StorageError {
-                kind: ErrorKind::Api,
-                msg: format!("API Error in delete_item: {}", e.to_string()),
-            }),
         }
+
+        info!(
+            "list_services(): succeed to return services: services-size={}",
+            services.len()
+        );
+        Ok(services.into_values().collect())
+    }
+
+    async fn query_weights(&self, name: &str) -> Result<RevisionWeights, StorageError> {
+        let res = observe_dynamodb_request(
+            "get_item",
+            self.dynamodb_client
+                .get_item()
+                .table_name(&self.table_name)
+                .set_key(Some(build_revision_weights_key(name)))
+                .send(),
+        )
+        .await
+        .map_err(|e| build_api_error("get_item", e))?;
+        let weights = match res.item {
+            Some(mut item) => {
+                convert_ddb_weights_to_domain_weights(extract_map(&mut item, "weights")?)?
+            }
+            None => RevisionWeights::new(),
+        };
+        info!(
+            "query_revision_weights(): succeed to return weights: service={}, weights={:?}",
+            name, weights
+        );
+        Ok(weights)
+    }
+
+    async fn store_weights(
+        &self,
+        name: &str,
+        weights: RevisionWeights,
+    ) -> Result<(), StorageError> {
+        if weights.is_empty() {
+            observe_dynamodb_request(
+                "delete_item",
+                self.dynamodb_client
+                    .delete_item()
+                    .table_name(&self.table_name)
+                    .set_key(Some(build_revision_weights_key(name)))
+                    .send(),
+            )
+            .await
+            .map_err(|e| build_api_error("delete_item", e))?;
+        } else {
+            // No expire_time, so that DynamoDB's TTL never removes the item.
+            let mut item = build_revision_weights_key(name);
+            let weights_map = weights
+                .iter()
+                .map(|(k, v)| (k.to_owned(), build_number_attr(u64::from(*v))))
+                .collect();
+            item.insert("weights".to_owned(), AttributeValue::M(weights_map));
+            observe_dynamodb_request(
+                "put_item",
+                self.dynamodb_client
+                    .put_item()
+                    .table_name(&self.table_name)
+                    .set_item(Some(item))
+                    .send(),
+            )
+            .await
+            .map_err(|e| build_api_error("put_item", e))?;
+        }
+        info!(
+            "store_revision_weights(): succeed to store weights: service={}, weights={:?}",
+            name, weights
+        );
+        Ok(())
+    }
+}
+
+impl Storage for StorageImpl {
+    type E = StorageError;
+
+    fn query_items(&self, name: &str) -> StorageFuture<Vec<Host>, Self::E> {
+        let st = self.clone();
+        let name = name.to_owned();
+        Box::pin(async move { st.query_hosts(&name).await })
+    }
+
+    fn store_item(&self, name: &str, host: Host) -> StorageFuture<(), Self::E> {
+        let st = self.clone();
+        let name = name.to_owned();
+        Box::pin(async move { st.store_host(&name, host).await })
+    }
+
+    fn delete_item(
+        &self,
+        name: &str,
+        ip: String,
+        port: u64,
+    ) -> StorageFuture<Option<Host>, Self::E> {
+        let st = self.clone();
+        let name = name.to_owned();
+        Box::pin(async move { st.delete_host(&name, ip, port).await })
+    }
+
+    fn refresh_item(
+        &self,
+        name: &str,
+        ip: String,
+        port: u64,
+        last_check_in: String,
+        expire_time: u64,
+    ) -> StorageFuture<Option<Host>, Self::E> {
+        let st = self.clone();
+        let name = name.to_owned();
+        Box::pin(async move {
+            st.refresh_host(&name, ip, port, last_check_in, expire_time)
+                .await
+        })
+    }
+
+    fn update_status(
+        &self,
+        name: &str,
+        ip: String,
+        port: u64,
+        status: HealthStatus,
+    ) -> StorageFuture<Option<Host>, Self::E> {
+        let st = self.clone();
+        let name = name.to_owned();
+        Box::pin(async move { st.update_host_status(&name, ip, port, status).await })
+    }
+
+    fn list_services(&self) -> StorageFuture<Vec<ServiceSummary>, Self::E> {
+        let st = self.clone();
+        Box::pin(async move { st.summarize_services().await })
+    }
+
+    fn query_revision_weights(&self, name: &str) -> StorageFuture<RevisionWeights, Self::E> {
+        let st = self.clone();
+        let name = name.to_owned();
+        Box::pin(async move { st.query_weights(&name).await })
+    }
+
+    fn store_revision_weights(
+        &self,
+        name: &str,
+        weights: RevisionWeights,
+    ) -> StorageFuture<(), Self::E> {
+        let st = self.clone();
+        let name = name.to_owned();
+        Box::pin(async move { st.store_weights(&name, weights).await })
     }
 
     fn ttl(&self) -> u64 {
@@ -177,54 +559,75 @@ where
     }
 }
 
-fn build_query_input(table_name: String, name: &str) -> QueryInput {
-    let mut attr_service: AttributeValue = Default::default();
-    attr_service.s = Some(name.to_owned());
-    let mut expression_attribute_values: HashMap<String, AttributeValue> = HashMap::new();
-    expression_attribute_values.insert(":service_val".to_owned(), attr_service);
-
-    let mut query_input: QueryInput = Default::default();
-    query_input.table_name = table_name;
-    query_input.expression_attribute_values = Some(expression_attribute_values);
-    query_input.key_condition_expression = Some("service = :service_val".to_owned());
-    query_input
+// Records the latency of a DynamoDB API call when it completes.
+async fn observe_dynamodb_request<F: Future>(operation: &'static str, f: F) -> F::Output {
+    let started = Instant::now();
+    let res = f.await;
+    metrics::observe_dynamodb_request(operation, started.elapsed());
+    res
 }
 
-fn build_put_item_input(table_name: String, name: &str, host: Host) -> PutItemInput {
-    let mut put_item_input: PutItemInput = Default::default();
-    put_item_input.table_name = table_name;
-    put_item_input.item = convert_domain_host_to_ddb_host(&name, host);
-    put_item_input
+fn build_api_error<E, R>(op: &str, e: SdkError<E, R>) -> StorageError
+where
+    E: error::Error + ProvideErrorMetadata + 'static,
+    R: fmt::Debug,
+{
+    let kind = match e {
+        SdkError::TimeoutError(_) => ErrorKind::Timeout,
+        SdkError::DispatchFailure(ref f) if f.is_timeout() => ErrorKind::Timeout,
+        _ => match e.code() {
+            Some("ProvisionedThroughputExceededException")
+            | Some("RequestLimitExceeded")
+            | Some("ThrottlingException") => ErrorKind::Throttled,
+            _ => ErrorKind::Api,
+        },
+    };
+    StorageError::new(
+        kind,
+        format!("API Error in {}: {}", op, DisplayErrorContext(e)),
+    )
 }
 
-fn build_delete_item_input(table_name: String, name: &str, ip: &str, port: u64) -> DeleteItemInput {
-    let mut delete_item_input: DeleteItemInput = Default::default();
-    delete_item_input.table_name = table_name;
+fn build_primary_key(name: &str, ip: &str, port: u64) -> HashMap<String, AttributeValue> {
     let mut pk = HashMap::new();
     pk.insert("service".to_owned(), build_string_attr(name.to_owned()));
-    let ip_and_port = format!("{}:{}", ip, port);
+    let ip_and_port = join_ip_port(ip, port);
     pk.insert("ip_port".to_owned(), build_string_attr(ip_and_port));
-    delete_item_input.key = pk;
-    delete_item_input.return_values = Some("ALL_OLD".to_owned());
-    delete_item_input
+    pk
+}
+
+fn build_revision_weights_key(name: &str) -> HashMap<String, AttributeValue> {
+    let mut pk = HashMap::new();
+    pk.insert("service".to_owned(), build_string_attr(name.to_owned()));
+    pk.insert(
+        "ip_port".to_owned(),
+        build_string_attr(REVISION_WEIGHTS_IP_PORT.to_owned()),
+    );
+    pk
 }
 
 fn convert_domain_host_to_ddb_host(name: &str, host: Host) -> HashMap<String, AttributeValue> {
     let mut map = HashMap::new();
     map.insert("service".to_owned(), build_string_attr(name.to_owned()));
-    let ip_port = format!("{}:{}", host.ip_address, host.port);
+    let ip_port = join_ip_port(&host.ip_address, u64::from(host.port));
     map.insert("ip_port".to_owned(), build_string_attr(ip_port));
     map.insert(
         "last_check_in".to_owned(),
         build_string_attr(host.last_check_in),
     );
-    let mut v: AttributeValue = Default::default();
-    v.n = Some(host.expire_time.to_string());
-    map.insert("expire_time".to_owned(), v);
+    map.insert(
+        "expire_time".to_owned(),
+        build_number_attr(host.expire_time),
+    );
     map.insert("revision".to_owned(), build_string_attr(host.revision));
-    let mut v: AttributeValue = Default::default();
-    v.m = Some(convert_domain_tag_to_ddb_tag(host.tags));
-    map.insert("tags".to_owned(), v);
+    map.insert(
+        "health_status".to_owned(),
+        build_string_attr(host.health_status.as_str().to_owned()),
+    );
+    map.insert(
+        "tags".to_owned(),
+        AttributeValue::M(convert_domain_tag_to_ddb_tag(host.tags)),
+    );
     map
 }
 
@@ -233,67 +636,194 @@ fn convert_domain_tag_to_ddb_tag(tag: Tag) -> HashMap<String, AttributeValue> {
     map.insert("az".to_owned(), build_string_attr(tag.az));
     map.insert("region".to_owned(), build_string_attr(tag.region));
     map.insert("instance_id".to_owned(), build_string_attr(tag.instance_id));
-    let mut v: AttributeValue = Default::default();
-    v.bool = Some(tag.canary);
-    map.insert("canary".to_owned(), v);
+    map.insert("canary".to_owned(), AttributeValue::Bool(tag.canary));
 
     if let Some(weight) = tag.load_balancing_weight {
-        let v = AttributeValue {
-            n: Some(weight.to_string()),
-            ..Default::default()
-        };
-        map.insert("load_balancing_weight".to_owned(), v);
+        map.insert(
+            "load_balancing_weight".to_owned(),
+            AttributeValue::N(weight.to_string()),
+        );
+    }
+    if !tag.labels.is_empty() {
+        let labels = tag
+            .labels
+            .into_iter()
+            .map(|(k, v)| (k, convert_domain_label_to_ddb_label(v)))
+            .collect();
+        map.insert("labels".to_owned(), AttributeValue::M(labels));
     }
 
     map
 }
 
+fn convert_domain_label_to_ddb_label(v: LabelValue) -> AttributeValue {
+    match v {
+        LabelValue::Bool(b) => AttributeValue::Bool(b),
+        LabelValue::Number(n) => AttributeValue::N(n.to_string()),
+        LabelValue::String(s) => AttributeValue::S(s),
+    }
+}
+
 fn build_string_attr(s: String) -> AttributeValue {
-    let mut v: AttributeValue = Default::default();
-    v.s = Some(s);
-    v
+    AttributeValue::S(s)
+}
+
+fn build_number_attr(n: u64) -> AttributeValue {
+    AttributeValue::N(n.to_string())
+}
+
+// Extracts expire_time, last_check_in and revision projected by the scan of list_services.
+fn extract_summary_attrs(
+    m: &mut HashMap<String, AttributeValue>,
+) -> Result<(u64, String, String), StorageError> {
+    Ok((
+        extract_number(m, "expire_time")?,
+        extract_string(m, "last_check_in")?,
+        extract_string(m, "revision")?,
+    ))
 }
 
 fn convert_ddb_host_to_domain_host(
     name: &str,
     mut h: HashMap<String, AttributeValue>,
 ) -> Result<Host, StorageError> {
-    let tag = convert_ddb_tags_to_domain_tag(extract_map(&mut h, "tags")?)?;
+    let tag = convert_ddb_tags_to_domain_tag(extract_map(&mut h, "tags")?).map_err(|mut e| {
+        e.key = e.key.map(|k| format!("tags.{}", k));
+        e
+    })?;
 
-    let addr_and_port_string = extract_string(&mut h, "ip_port")?;
-    let addr_and_port: Vec<&str> = addr_and_port_string.split(':').collect();
-    if addr_and_port.len() != 2 {
-        return Err(build_data_error(format!(
-            "\"{}\" must be formated with colon like \"ip:port\"",
-            addr_and_port_string
-        )));
-    }
-    let port_string = addr_and_port[1].to_string();
-    let port = match port_string.parse() {
-        Ok(v) => v,
-        Err(_e) => {
-            return Err(build_data_error(format!(
-                "port value must be a valid integer: {}",
-                port_string
-            )))
-        }
-    };
+    let (ip_address, port) = split_ip_port(&extract_string(&mut h, "ip_port")?)?;
     Ok(Host {
-        ip_address: addr_and_port[0].to_string(),
+        ip_address,
         port,
         last_check_in: extract_string(&mut h, "last_check_in")?,
         expire_time: extract_number(&mut h, "expire_time")?,
         revision: extract_string(&mut h, "revision")?,
         service: name.to_owned(),
         tags: tag,
+        health_status: extract_health_status(&mut h, "health_status")?,
     })
 }
 
-fn build_data_error(msg: String) -> StorageError {
-    StorageError {
-        kind: ErrorKind::Data,
-        msg,
+fn convert_ddb_weights_to_domain_weights(
+    m: HashMap<String, AttributeValue>,
+) -> Result<RevisionWeights, StorageError> {
+    m.into_iter()
+        .map(|(revision, v)| {
+            let key = format!("weights.{}", revision);
+            match v {
+                AttributeValue::N(ref n) => match n.parse() {
+                    Ok(w) => Ok((revision, w)),
+                    Err(_e) => Err(build_data_error(
+                        &key,
+                        format!(
+                            "Key \"{}\" is expected to be a Number (u32) value but is not: {}",
+                            key, n
+                        ),
+                    )),
+                },
+                _ => Err(build_data_error(
+                    &key,
+                    format!("Key \"{}\" is expected to be a Number but is not", key),
+                )),
+            }
+        })
+        .collect()
+}
+
+// The environment whose hosts are stored under the bare service name, which is the only one
+// tables written before environments were introduced have.
+const LEGACY_ENV: &str = "production";
+
+// Builds the `service` partition key of a service in a namespace and an environment, like
+// "team/user@staging". '/' and '@' are not allowed in names, so keys of different namespaces or
+// environments never collide.
+pub(crate) fn build_service_key(namespace: Option<&str>, name: &str, env: &str) -> String {
+    let mut key = match namespace {
+        Some(ns) => format!("{}/{}", ns, name),
+        None => name.to_owned(),
+    };
+    if env != LEGACY_ENV {
+        key.push('@');
+        key.push_str(env);
     }
+    key
+}
+
+// Splits the key built by build_service_key into the namespace, the service name and the
+// environment.
+pub(crate) fn split_service_key(key: &str) -> (Option<&str>, &str, &str) {
+    let (rest, env) = match key.rfind('@') {
+        Some(i) => (&key[..i], &key[i + 1..]),
+        None => (key, LEGACY_ENV),
+    };
+    match rest.find('/') {
+        Some(i) => (Some(&rest[..i]), &rest[i + 1..], env),
+        None => (None, rest, env),
+    }
+}
+
+// Builds the "ip:port" sort key used by every backend. IPv6 addresses are bracketed like
+// "[::1]:80" and written in their canonical form, so that the key is unambiguous and the same
+// host always maps to the same key.
+pub(crate) fn join_ip_port(ip: &str, port: u64) -> String {
+    match trim_brackets(ip).parse::<IpAddr>() {
+        Ok(IpAddr::V6(addr)) => format!("[{}]:{}", addr, port),
+        Ok(IpAddr::V4(addr)) => format!("{}:{}", addr, port),
+        Err(_) => format!("{}:{}", ip, port),
+    }
+}
+
+// Splits the sort key built by join_ip_port into its parts. Unbracketed IPv6 addresses written
+// before they were bracketed are read by splitting on the last colon.
+pub(crate) fn split_ip_port(ip_port: &str) -> Result<(String, u16), StorageError> {
+    let (addr, port_string) = match ip_port.rfind(':') {
+        Some(i) => (&ip_port[..i], &ip_port[i + 1..]),
+        None => {
+            return Err(build_data_error(
+                "ip_port",
+                format!(
+                    "\"{}\" must be formated with colon like \"ip:port\" or \"[ip]:port\"",
+                    ip_port
+                ),
+            ))
+        }
+    };
+    let port = match port_string.parse() {
+        Ok(v) => v,
+        Err(_e) => {
+            return Err(build_data_error(
+                "ip_port",
+                format!("port value must be a valid integer: {}", port_string),
+            ))
+        }
+    };
+    Ok((trim_brackets(addr).to_string(), port))
+}
+
+// Strips the brackets of an IPv6 address like "[::1]".
+pub(crate) fn trim_brackets(ip: &str) -> &str {
+    if ip.starts_with('[') && ip.ends_with(']') {
+        &ip[1..ip.len() - 1]
+    } else {
+        ip
+    }
+}
+
+pub(crate) fn fetch_epoch_now() -> Result<u64, StorageError> {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(v) => Ok(v.as_secs()),
+        Err(_) => Err(StorageError::new(
+            ErrorKind::System,
+            "Cloud not fetch system time".to_owned(),
+        )),
+    }
+}
+
+pub(crate) fn build_data_error(key: &str, msg: String) -> StorageError {
+    let mut e = StorageError::new(ErrorKind::Data, msg);
+    e.key = Some(key.to_owned());
+    e
 }
 
 fn convert_ddb_tags_to_domain_tag(
@@ -305,70 +835,153 @@ fn convert_ddb_tags_to_domain_tag(
         instance_id: extract_string(&mut tag_map, "instance_id")?,
         canary: extract_bool(&mut tag_map, "canary")?,
         load_balancing_weight: extract_u8(&mut tag_map, "load_balancing_weight")?,
+        labels: extract_labels(&mut tag_map, "labels")?,
     })
 }
 
+// Items written before health_status was introduced are healthy.
+fn extract_health_status(
+    m: &mut HashMap<String, AttributeValue>,
+    k: &str,
+) -> Result<HealthStatus, StorageError> {
+    match m.remove(k) {
+        Some(AttributeValue::S(s)) => HealthStatus::parse(&s).ok_or_else(|| {
+            build_data_error(
+                k,
+                format!(
+                    "Key \"{}\" is expected to be a health status but is not: {}",
+                    k, s
+                ),
+            )
+        }),
+        Some(_) => Err(build_data_error(
+            k,
+            format!("Key \"{}\" is expected to be a String but is not", k),
+        )),
+        None => Ok(HealthStatus::Healthy),
+    }
+}
+
+// Labels are optional, since hosts registered before labels were introduced have none.
+fn extract_labels(
+    m: &mut HashMap<String, AttributeValue>,
+    k: &str,
+) -> Result<BTreeMap<String, LabelValue>, StorageError> {
+    let labels = match m.remove(k) {
+        Some(AttributeValue::M(v)) => v,
+        Some(_) => {
+            return Err(build_data_error(
+                k,
+                format!("Key \"{}\" is expected to be a Map but is not", k),
+            ))
+        }
+        None => return Ok(BTreeMap::new()),
+    };
+    labels
+        .into_iter()
+        .map(|(name, v)| {
+            let key = format!("{}.{}", k, name);
+            let value = match v {
+                AttributeValue::Bool(b) => LabelValue::Bool(b),
+                AttributeValue::N(n) => match n.parse() {
+                    Ok(n) => LabelValue::Number(n),
+                    Err(_e) => {
+                        return Err(build_data_error(
+                            &key,
+                            format!(
+                                "Key \"{}\" is expected to be a Number but is not: {}",
+                                key, n
+                            ),
+                        ))
+                    }
+                },
+                AttributeValue::S(s) => LabelValue::String(s),
+                _ => {
+                    return Err(build_data_error(
+                        &key,
+                        format!(
+                            "Key \"{}\" is expected to be a String, Number or Boolean but is not",
+                            key
+                        ),
+                    ))
+                }
+            };
+            Ok((name, value))
+        })
+        .collect()
+}
+
 fn extract_string(
     m: &mut HashMap<String, AttributeValue>,
     k: &str,
 ) -> Result<String, StorageError> {
-    extract(m, k)?.s.ok_or_else(|| {
-        build_data_error(format!(
-            "Key \"{}\" is expected to be a String but is not",
-            k
-        ))
-    })
+    match extract(m, k)? {
+        AttributeValue::S(s) => Ok(s),
+        _ => Err(build_data_error(
+            k,
+            format!("Key \"{}\" is expected to be a String but is not", k),
+        )),
+    }
 }
 
 fn extract_bool(m: &mut HashMap<String, AttributeValue>, k: &str) -> Result<bool, StorageError> {
-    extract(m, k)?.bool.ok_or_else(|| {
-        build_data_error(format!(
-            "Key \"{}\" is expected to be a Boolean but is not",
-            k
-        ))
-    })
+    match extract(m, k)? {
+        AttributeValue::Bool(b) => Ok(b),
+        _ => Err(build_data_error(
+            k,
+            format!("Key \"{}\" is expected to be a Boolean but is not", k),
+        )),
+    }
 }
 
 fn extract_number(m: &mut HashMap<String, AttributeValue>, k: &str) -> Result<u64, StorageError> {
-    extract(m, k)?
-        .n
-        .ok_or_else(|| {
-            build_data_error(format!(
-                "Key \"{}\" is expected to be a Number but is not",
-                k
-            ))
-        })
-        .and_then(|s| match s.parse() {
+    match extract(m, k)? {
+        AttributeValue::N(s) => match s.parse() {
             Ok(u) => Ok(u),
-            Err(_e) => Err(build_data_error(format!(
-                "Key \"{}\" is expected to be a Number (u64) value but is not: {}",
-                k, s,
-            ))),
-        })
+            Err(_e) => Err(build_data_error(
+                k,
+                format!(
+                    "Key \"{}\" is expected to be a Number (u64) value but is not: {}",
+                    k, s,
+                ),
+            )),
+        },
+        _ => Err(build_data_error(
+            k,
+            format!("Key \"{}\" is expected to be a Number but is not", k),
+        )),
+    }
 }
 
 fn extract_map(
     m: &mut HashMap<String, AttributeValue>,
     k: &str,
 ) -> Result<HashMap<String, AttributeValue>, StorageError> {
-    extract(m, k)?.m.ok_or_else(|| {
-        build_data_error(format!("Key \"{}\" is expected to be a Map but is not", k))
-    })
+    match extract(m, k)? {
+        AttributeValue::M(v) => Ok(v),
+        _ => Err(build_data_error(
+            k,
+            format!("Key \"{}\" is expected to be a Map but is not", k),
+        )),
+    }
 }
 
 fn extract_u8(
     m: &mut HashMap<String, AttributeValue>,
     k: &str,
 ) -> Result<Option<u8>, StorageError> {
-    match m.remove(k).and_then(|v| v.n) {
-        Some(s) => match s.parse() {
+    match m.remove(k) {
+        Some(AttributeValue::N(s)) => match s.parse() {
             Ok(u) => Ok(Some(u)),
-            Err(_e) => Err(build_data_error(format!(
-                "Key \"{}\" is expected to be a Number (u8) value but is not: {}",
-                k, s,
-            ))),
+            Err(_e) => Err(build_data_error(
+                k,
+                format!(
+                    "Key \"{}\" is expected to be a Number (u8) value but is not: {}",
+                    k, s,
+                ),
+            )),
         },
-        None => Ok(None),
+        _ => Ok(None),
     }
 }
 
@@ -377,5 +990,54 @@ fn extract(
     k: &str,
 ) -> Result<AttributeValue, StorageError> {
     m.remove(k)
-        .ok_or_else(|| build_data_error(format!("Missing required value for key: {}", k)))
+        .ok_or_else(|| build_data_error(k, format!("Missing required value for key: {}", k)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn join_ip_port_of_each_family() {
+        assert_eq!(join_ip_port("10.0.0.1", 80), "10.0.0.1:80");
+        assert_eq!(join_ip_port("::1", 80), "[::1]:80");
+        assert_eq!(join_ip_port("[::1]", 80), "[::1]:80");
+        // IPv6 addresses are written in their canonical form.
+        assert_eq!(join_ip_port("2001:db8:0:0:0:0:0:1", 80), "[2001:db8::1]:80");
+        assert_eq!(join_ip_port("host.local", 80), "host.local:80");
+    }
+
+    #[test]
+    fn split_ip_port_of_each_family() {
+        assert_eq!(
+            split_ip_port("10.0.0.1:80").unwrap(),
+            ("10.0.0.1".to_owned(), 80)
+        );
+        assert_eq!(split_ip_port("[::1]:80").unwrap(), ("::1".to_owned(), 80));
+        // Written before IPv6 addresses were bracketed.
+        assert_eq!(split_ip_port("::1:80").unwrap(), ("::1".to_owned(), 80));
+    }
+
+    #[test]
+    fn split_ip_port_of_malformed_key() {
+        let e = split_ip_port("10.0.0.1").unwrap_err();
+        assert!(matches!(e.kind, ErrorKind::Data));
+        assert_eq!(e.key.as_deref(), Some("ip_port"));
+        assert!(split_ip_port("10.0.0.1:port").is_err());
+        assert!(split_ip_port("10.0.0.1:65536").is_err());
+    }
+
+    #[test]
+    fn build_and_split_service_key() {
+        let cases = [
+            (None, "user", "production", "user"),
+            (None, "user", "staging", "user@staging"),
+            (Some("team"), "user", "production", "team/user"),
+            (Some("team"), "user", "staging", "team/user@staging"),
+        ];
+        for (namespace, name, env, key) in cases.iter() {
+            assert_eq!(build_service_key(*namespace, name, env), *key);
+            assert_eq!(split_service_key(key), (*namespace, *name, *env));
+        }
+    }
 }
@@ -0,0 +1,137 @@
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::sync::Arc;
+
+use rustls::crypto::ring;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::{ServerConnection, WebPkiClientVerifier};
+use rustls::{RootCertStore, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+use x509_parser::prelude::{FromDer, GeneralName, X509Certificate};
+
+use super::types::TlsConfig;
+
+// The verified certificate of a TLS client, put into request extensions.
+#[derive(Debug, Clone)]
+pub struct ClientCert {
+    // URIs of the subject alternative name extension.
+    pub uris: Vec<String>,
+}
+
+// The scheme of URI SANs naming services.
+const SERVICE_URI_PREFIX: &str = "sds://";
+
+impl ClientCert {
+    // A certificate may write a service named by one of its URIs, like "sds://user_service", or
+    // "sds://team-a/user_service" for a service in namespace "team-a". Neither namespaces nor
+    // service names contain '/', so a URI never names more than one service.
+    pub fn can_write(&self, name: &str, namespace: Option<&str>) -> bool {
+        let expected = match namespace {
+            Some(ns) => format!("{}{}/{}", SERVICE_URI_PREFIX, ns, name),
+            None => format!("{}{}", SERVICE_URI_PREFIX, name),
+        };
+        self.uris.contains(&expected)
+    }
+}
+
+pub fn build_acceptor(c: &TlsConfig) -> io::Result<TlsAcceptor> {
+    let provider = Arc::new(ring::default_provider());
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(invalid_data)?;
+    let builder = match c.client_ca_file {
+        Some(ref path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(path)? {
+                roots.add(cert).map_err(invalid_data)?;
+            }
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider);
+            // Clients without a certificate can still read, and write with a token.
+            let verifier = if c.require_client_cert {
+                verifier
+            } else {
+                verifier.allow_unauthenticated()
+            };
+            builder.with_client_cert_verifier(verifier.build().map_err(invalid_data)?)
+        }
+        None if c.require_client_cert || c.authorize_by_san => {
+            return Err(invalid_data(
+                "client_ca_file is required to verify client certificates",
+            ))
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let key = load_private_key(&c.key_file)?;
+    let mut config = builder
+        .with_single_cert(load_certs(&c.cert_file)?, key)
+        .map_err(invalid_data)?;
+    // Serve both HTTP/2 and HTTP/1.1 like the plaintext listener.
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+// Returns the certificate the client presented, which the verifier has already checked.
+pub fn client_cert(conn: &ServerConnection) -> Option<ClientCert> {
+    let der = conn.peer_certificates()?.first()?;
+    let (_, cert) = X509Certificate::from_der(der.as_ref()).ok()?;
+    let mut uris = Vec::new();
+    if let Ok(Some(san)) = cert.subject_alternative_name() {
+        for name in san.value.general_names.iter() {
+            if let GeneralName::URI(u) = name {
+                uris.push((*u).to_owned());
+            }
+        }
+    }
+    Some(ClientCert { uris })
+}
+
+fn load_certs(path: &str) -> io::Result<Vec<CertificateDer<'static>>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certs = rustls_pemfile::certs(&mut reader).collect::<io::Result<Vec<_>>>()?;
+    if certs.is_empty() {
+        return Err(invalid_data(format!("No certificate found in {}", path)));
+    }
+    Ok(certs)
+}
+
+fn load_private_key(path: &str) -> io::Result<PrivateKeyDer<'static>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    rustls_pemfile::private_key(&mut reader)?
+        .ok_or_else(|| invalid_data(format!("No private key found in {}", path)))
+}
+
+fn invalid_data<E: Into<Box<dyn std::error::Error + Send + Sync>>>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn build_cert(uris: &[&str]) -> ClientCert {
+        ClientCert {
+            uris: uris.iter().map(|u| (*u).to_owned()).collect(),
+        }
+    }
+
+    #[test]
+    fn can_write_named_services() {
+        let cert = build_cert(&["sds://user", "sds://team-a/payment"]);
+        assert!(cert.can_write("user", None));
+        assert!(cert.can_write("payment", Some("team-a")));
+        assert!(!cert.can_write("payment", None));
+        assert!(!cert.can_write("user", Some("team-a")));
+        assert!(!cert.can_write("payment", Some("team-b")));
+    }
+
+    #[test]
+    fn can_write_services_with_dots_in_either_scope_only() {
+        let cert = build_cert(&["sds://user.team-a"]);
+        assert!(cert.can_write("user.team-a", None));
+        assert!(!cert.can_write("user", Some("team-a")));
+
+        let cert = build_cert(&["sds://team-a/user"]);
+        assert!(!cert.can_write("user.team-a", None));
+    }
+}
@@ -1,28 +1,252 @@
+use futures::future::BoxFuture;
 use serde_derive::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
 use std::error;
 use std::fmt;
+use std::net::IpAddr;
+
+use super::auth::Auth;
+use super::storage::{split_service_key, StorageError};
+
+pub type StorageFuture<T, E> = BoxFuture<'static, Result<T, E>>;
+
+// Traffic weights of each active revision of a service, keyed by the revision. Hosts of other
+// revisions receive no traffic while any is set.
+pub type RevisionWeights = BTreeMap<String, u32>;
 
 pub trait Storage: Send + Sync + Clone + 'static {
-    type E: fmt::Display + error::Error;
-    fn query_items(&self, name: &str) -> Result<Vec<Host>, Self::E>;
-    fn store_item(&self, name: &str, host: Host) -> Result<(), Self::E>;
-    fn delete_item(&self, name: &str, ip: String, port: u64) -> Result<Option<Host>, Self::E>;
+    // Converted into StorageError to build an error response from its kind.
+    type E: fmt::Display + error::Error + Send + Into<StorageError> + 'static;
+    fn query_items(&self, name: &str) -> StorageFuture<Vec<Host>, Self::E>;
+    fn store_item(&self, name: &str, host: Host) -> StorageFuture<(), Self::E>;
+    fn delete_item(
+        &self,
+        name: &str,
+        ip: String,
+        port: u64,
+    ) -> StorageFuture<Option<Host>, Self::E>;
+    // Bumps expire_time and last_check_in of a live host without touching other attributes.
+    // Returns None when the host does not exist or is already expired.
+    fn refresh_item(
+        &self,
+        name: &str,
+        ip: String,
+        port: u64,
+        last_check_in: String,
+        expire_time: u64,
+    ) -> StorageFuture<Option<Host>, Self::E>;
+    // Sets health_status of a live host. Returns None when the host does not exist or is already
+    // expired.
+    fn update_status(
+        &self,
+        name: &str,
+        ip: String,
+        port: u64,
+        status: HealthStatus,
+    ) -> StorageFuture<Option<Host>, Self::E>;
+    // Returns every service found in the storage, in ascending order of the name.
+    fn list_services(&self) -> StorageFuture<Vec<ServiceSummary>, Self::E>;
+    // Returns the rollout weights of revisions of a service, which are empty when not set.
+    fn query_revision_weights(&self, name: &str) -> StorageFuture<RevisionWeights, Self::E>;
+    // Replaces the rollout weights of a service. Empty weights clear them.
+    fn store_revision_weights(
+        &self,
+        name: &str,
+        weights: RevisionWeights,
+    ) -> StorageFuture<(), Self::E>;
     fn ttl(&self) -> u64;
 }
 
 #[derive(Debug, Clone)]
 pub struct Config {
+    // "::" listens on both IPv4 and IPv6 where the OS allows dual-stack sockets.
+    pub bind_addr: IpAddr,
     pub listen_port: u16,
+    // The environment of requests without one, and of hosts served through EDS and CDS.
+    pub env: String,
+    pub cds: CdsConfig,
+    pub eds: EdsConfig,
+    // Every request is accepted when missing.
+    pub auth: Option<Auth>,
+    // Serves plaintext HTTP when missing.
+    pub tls: Option<TlsConfig>,
+}
+
+// Settings loaded from the JSON file given by CONFIG_FILE env.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct FileConfig {
+    pub cds: CdsConfig,
+    pub eds: EdsConfig,
+    pub auth: AuthConfig,
+    pub tls: Option<TlsConfig>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct AuthConfig {
+    // Requires a token to write registrations when given.
+    pub tokens_file: Option<String>,
+    pub protect_reads: bool,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct TlsConfig {
+    // PEM files of the server certificate chain and its private key.
+    pub cert_file: String,
+    pub key_file: String,
+    // PEM file of the CAs to verify client certificates with. Client certificates are not
+    // requested when missing.
+    #[serde(default)]
+    pub client_ca_file: Option<String>,
+    // Rejects TLS handshakes without a client certificate.
+    #[serde(default)]
+    pub require_client_cert: bool,
+    // Lets a client certificate write the services named by its URI SANs, like "sds://team/user".
+    #[serde(default)]
+    pub authorize_by_san: bool,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct EdsConfig {
+    // Another filter_metadata namespace to emit host labels under, in addition to "envoy.lb".
+    pub labels_namespace: Option<String>,
+    // Prefers localities close to the requesting node, so that Envoy only sends traffic to
+    // farther ones on failover.
+    pub locality_priority: LocalityPriority,
+    // Enables Envoy's locality weighted load balancing with these weights when not empty.
+    pub locality_weights: Vec<LocalityWeight>,
+    pub services: HashMap<String, EdsServiceConfig>,
+    // How often gRPC streams query their subscribed services to find changes made through other
+    // instances or by expiration. 1000 when missing.
+    pub stream_poll_interval_ms: Option<u64>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct EdsServiceConfig {
+    // The share of traffic in percent sent to canary hosts, regardless of how many canary and
+    // non-canary hosts there are. Weights are left as registered when missing.
+    pub canary_percentage: Option<u32>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum LocalityPriority {
+    // Every locality has priority 0.
+    #[default]
+    Disabled,
+    // The node's region first, then other regions.
+    Region,
+    // The node's zone first, then other zones in its region, then other regions.
+    Zone,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct LocalityWeight {
+    pub region: String,
+    // Matches every zone of the region when missing.
+    pub zone: Option<String>,
+    pub weight: u32,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct CdsConfig {
+    // The name of the cluster pointing to sds in Envoy's config, used as the EDS config source.
+    pub eds_cluster_name: String,
+    pub refresh_delay: String,
+    pub defaults: ClusterConfig,
+    // Per-service overrides of `defaults`.
+    pub services: HashMap<String, ClusterConfig>,
+}
+
+impl Default for CdsConfig {
+    fn default() -> Self {
+        CdsConfig {
+            eds_cluster_name: "sds".to_owned(),
+            refresh_delay: "1s".to_owned(),
+            defaults: ClusterConfig {
+                connect_timeout: Some("0.25s".to_owned()),
+                lb_policy: Some("ROUND_ROBIN".to_owned()),
+            },
+            services: HashMap::new(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct ClusterConfig {
+    pub connect_timeout: Option<String>,
+    pub lb_policy: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Registration {
     pub service: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub namespace: Option<String>,
     pub env: String,
     pub hosts: Vec<Host>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
+pub struct Services {
+    pub services: Vec<ServiceSummary>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ServiceSummary {
+    pub service: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub namespace: Option<String>,
+    pub env: String,
+    // The number of every host including expired ones.
+    pub hosts: u64,
+    pub healthy_hosts: u64,
+    pub expired_hosts: u64,
+    // The revision of the host which checked in most recently.
+    pub latest_revision: Option<String>,
+    pub latest_check_in: Option<String>,
+}
+
+impl ServiceSummary {
+    // Takes the key of the service in the storage, which includes its namespace and environment.
+    pub fn new(key: String) -> Self {
+        let (namespace, service, env) = split_service_key(&key);
+        ServiceSummary {
+            service: service.to_owned(),
+            namespace: namespace.map(str::to_owned),
+            env: env.to_owned(),
+            hosts: 0,
+            healthy_hosts: 0,
+            expired_hosts: 0,
+            latest_revision: None,
+            latest_check_in: None,
+        }
+    }
+
+    pub fn add_host(&mut self, expire_time: u64, last_check_in: &str, revision: &str, now: u64) {
+        self.hosts += 1;
+        if expire_time >= now {
+            self.healthy_hosts += 1;
+        } else {
+            self.expired_hosts += 1;
+        }
+        // last_check_in is formatted in UTC, so it can be compared as a string.
+        let is_latest = match self.latest_check_in {
+            Some(ref v) => v.as_str() < last_check_in,
+            None => true,
+        };
+        if is_latest {
+            self.latest_check_in = Some(last_check_in.to_owned());
+            self.latest_revision = Some(revision.to_owned());
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Host {
     pub ip_address: String,
     pub port: u16,
@@ -31,9 +255,44 @@ pub struct Host {
     pub revision: String,
     pub service: String,
     pub tags: Tag,
+    // Kept across re-registrations of a live host, so that a draining host stays draining.
+    #[serde(default)]
+    pub health_status: HealthStatus,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+// The same values as Envoy's core.HealthStatus, except UNKNOWN and TIMEOUT.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum HealthStatus {
+    #[default]
+    Healthy,
+    Draining,
+    Unhealthy,
+    Degraded,
+}
+
+impl HealthStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            HealthStatus::Healthy => "HEALTHY",
+            HealthStatus::Draining => "DRAINING",
+            HealthStatus::Unhealthy => "UNHEALTHY",
+            HealthStatus::Degraded => "DEGRADED",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<HealthStatus> {
+        match s {
+            "HEALTHY" => Some(HealthStatus::Healthy),
+            "DRAINING" => Some(HealthStatus::Draining),
+            "UNHEALTHY" => Some(HealthStatus::Unhealthy),
+            "DEGRADED" => Some(HealthStatus::Degraded),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Tag {
     pub az: String,
     pub region: String,
@@ -41,4 +300,15 @@ pub struct Tag {
     pub canary: bool,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub load_balancing_weight: Option<u8>,
+    // Free-form labels emitted in the endpoint metadata, e.g. for Envoy's subset load balancing.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, LabelValue>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum LabelValue {
+    Bool(bool),
+    Number(serde_json::Number),
+    String(String),
 }
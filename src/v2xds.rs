@@ -1,11 +1,16 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
 use serde_derive::{Deserialize, Serialize};
 use serde_json;
+use sha2::{Digest, Sha256};
 
-use super::types::Host;
+use super::types::{
+    CdsConfig, EdsConfig, HealthStatus, Host, LabelValue, LocalityPriority, LocalityWeight,
+    RevisionWeights,
+};
 
 pub const EDS_TYPE_URL: &str = "type.googleapis.com/envoy.api.v2.ClusterLoadAssignment";
+pub const CDS_TYPE_URL: &str = "type.googleapis.com/envoy.api.v2.Cluster";
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct DiscoveryRequest {
@@ -21,12 +26,24 @@ pub struct DiscoveryRequest {
 pub struct EdsDiscoveryResponse {
     pub version_info: String,
     pub resources: Vec<ClusterLoadAssignment>,
+    pub type_url: String,
+    pub nonce: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CdsDiscoveryResponse {
+    pub version_info: String,
+    pub resources: Vec<Cluster>,
+    pub type_url: String,
+    pub nonce: String,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Node {
     pub id: String,
     pub cluster: String,
+    #[serde(default)]
+    pub locality: Option<Locality>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -48,9 +65,15 @@ pub struct ClusterLoadAssignment {
 pub struct LocalityLbEndpoints {
     pub locality: Locality,
     pub lb_endpoints: Vec<LbEndpoint>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub load_balancing_weight: Option<u32>,
+    #[serde(default)]
+    pub priority: u32,
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Hash)]
+// Envoy may omit either field of a node's locality.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[serde(default)]
 pub struct Locality {
     pub region: String,
     pub zone: String,
@@ -59,9 +82,10 @@ pub struct Locality {
 #[derive(Serialize, Deserialize, Debug)]
 pub struct LbEndpoint {
     pub endpoint: Endpoint,
+    pub health_status: HealthStatus,
     pub metadata: Metadata,
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub load_balancing_weight: Option<u8>,
+    pub load_balancing_weight: Option<u32>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -82,7 +106,16 @@ pub struct SocketAddress {
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Metadata {
-    pub filter_metadata: HashMap<String, LbFilterMetadata>,
+    // Ordered, so that the serialized output and thus version_info are stable.
+    pub filter_metadata: BTreeMap<String, FilterMetadata>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(untagged)]
+pub enum FilterMetadata {
+    Lb(LbFilterMetadata),
+    // Host labels alone, under the namespace configured by `labels_namespace`.
+    Labels(BTreeMap<String, LabelValue>),
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -90,16 +123,63 @@ pub struct LbFilterMetadata {
     pub canary: bool,
     pub revision: String,
     pub instance_id: String,
+    #[serde(flatten)]
+    pub labels: BTreeMap<String, LabelValue>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Cluster {
+    #[serde(rename = "@type")]
+    pub type_url: String,
+    pub name: String,
+    #[serde(rename = "type")]
+    pub discovery_type: String,
+    pub connect_timeout: String,
+    pub lb_policy: String,
+    pub eds_cluster_config: EdsClusterConfig,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub common_lb_config: Option<CommonLbConfig>,
 }
 
-pub fn hosts_to_locality_lb_endpoints(mut hosts: Vec<Host>) -> Vec<LocalityLbEndpoints> {
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CommonLbConfig {
+    pub locality_weighted_lb_config: LocalityWeightedLbConfig,
+}
+
+// Envoy ignores load_balancing_weight of localities unless this is set.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LocalityWeightedLbConfig {}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct EdsClusterConfig {
+    pub eds_config: ConfigSource,
+    pub service_name: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ConfigSource {
+    pub api_config_source: ApiConfigSource,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ApiConfigSource {
+    pub api_type: String,
+    pub cluster_names: Vec<String>,
+    pub refresh_delay: String,
+}
+
+pub fn hosts_to_locality_lb_endpoints(
+    mut hosts: Vec<Host>,
+    node_locality: Option<&Locality>,
+    c: &EdsConfig,
+) -> Vec<LocalityLbEndpoints> {
     let mut lle_map: HashMap<Locality, Vec<LbEndpoint>> = HashMap::new();
     for h in hosts.drain(..) {
         let locality = Locality {
             region: h.tags.region.to_owned(),
             zone: h.tags.az.to_owned(),
         };
-        let le = convert_host_to_le(h);
+        let le = convert_host_to_le(h, c);
 
         match lle_map.entry(locality) {
             std::collections::hash_map::Entry::Vacant(e) => {
@@ -111,29 +191,214 @@ pub fn hosts_to_locality_lb_endpoints(mut hosts: Vec<Host>) -> Vec<LocalityLbEnd
         }
     }
 
+    // Keep the output stable so that unchanged hosts always produce the same version_info.
     let mut lle_vec = Vec::new();
-    for (k, v) in lle_map {
+    for (k, mut v) in lle_map {
+        v.sort_by(|a, b| {
+            let a = &a.endpoint.address.socket_address;
+            let b = &b.endpoint.address.socket_address;
+            (&a.address, a.port_value).cmp(&(&b.address, b.port_value))
+        });
         lle_vec.push(LocalityLbEndpoints {
+            load_balancing_weight: find_locality_weight(&k, &c.locality_weights),
+            priority: 0,
             locality: k,
             lb_endpoints: v,
         });
     }
+    lle_vec.sort_by(|a, b| a.locality.cmp(&b.locality));
+    if let Some(node_locality) = node_locality {
+        assign_priorities(&mut lle_vec, node_locality, c.locality_priority);
+    }
     lle_vec
 }
 
-fn convert_host_to_le(h: Host) -> LbEndpoint {
-    let mut filter_metadata = HashMap::new();
+// A rule for the zone takes precedence over the one for the whole region.
+fn find_locality_weight(locality: &Locality, weights: &[LocalityWeight]) -> Option<u32> {
+    let matches_region = |w: &&LocalityWeight| w.region == locality.region;
+    weights
+        .iter()
+        .filter(matches_region)
+        .find(|w| w.zone.as_ref() == Some(&locality.zone))
+        .or_else(|| {
+            weights
+                .iter()
+                .filter(matches_region)
+                .find(|w| w.zone.is_none())
+        })
+        .map(|w| w.weight)
+}
+
+// Envoy expects priorities to start from 0 without gaps, so the distances from the node are
+// ranked rather than used as they are.
+fn assign_priorities(lles: &mut [LocalityLbEndpoints], node: &Locality, p: LocalityPriority) {
+    let distance = |l: &Locality| -> u32 {
+        match p {
+            LocalityPriority::Disabled => 0,
+            LocalityPriority::Region => (l.region != node.region) as u32,
+            LocalityPriority::Zone => {
+                if l.region != node.region {
+                    2
+                } else {
+                    (l.zone != node.zone) as u32
+                }
+            }
+        }
+    };
+    let distances: Vec<u32> = lles.iter().map(|lle| distance(&lle.locality)).collect();
+    let mut ranks = distances.clone();
+    ranks.sort_unstable();
+    ranks.dedup();
+    for (lle, d) in lles.iter_mut().zip(distances) {
+        lle.priority = ranks.binary_search(&d).unwrap_or(0) as u32;
+    }
+}
+
+// Endpoint weights of a priority are rescaled to sum up to about this when shares are applied,
+// which keeps the sum of every locality within u32 as Envoy requires.
+const SHARED_WEIGHT_TOTAL: f64 = 1_000_000.0;
+
+// The share of traffic of each group of endpoints.
+pub struct GroupShares {
+    pub shares: HashMap<String, u32>,
+    pub group_of: fn(&LbFilterMetadata) -> String,
+}
+
+impl GroupShares {
+    // Sends `percentage` of the traffic to canary endpoints and the rest to the others.
+    pub fn canary(percentage: u32) -> GroupShares {
+        let percentage = percentage.min(100);
+        let mut shares = HashMap::new();
+        shares.insert(true.to_string(), percentage);
+        shares.insert(false.to_string(), 100 - percentage);
+        GroupShares {
+            shares,
+            group_of: |m| m.canary.to_string(),
+        }
+    }
+
+    // Sends traffic to the active revisions in proportion to their weights.
+    pub fn revisions(weights: &RevisionWeights) -> GroupShares {
+        GroupShares {
+            shares: weights.iter().map(|(k, v)| (k.to_owned(), *v)).collect(),
+            group_of: |m| m.revision.to_owned(),
+        }
+    }
+
+    fn share_of(&self, le: &LbEndpoint) -> u32 {
+        lb_filter_metadata(le)
+            .and_then(|m| self.shares.get(&(self.group_of)(m)))
+            .cloned()
+            .unwrap_or(0)
+    }
+
+    fn group_of(&self, le: &LbEndpoint) -> Option<String> {
+        lb_filter_metadata(le).map(self.group_of)
+    }
+}
+
+// Scales load_balancing_weight, so that each group of endpoints in a priority receives traffic in
+// proportion to its share however many endpoints the group has. Later shares split the traffic of
+// each group again, keeping the ratio given by earlier ones within the group.
+//
+// Endpoints of groups without a share are removed, unless no endpoint belongs to a group with one;
+// then those shares are ignored rather than leaving the cluster without endpoints.
+pub fn apply_group_shares(lles: &mut Vec<LocalityLbEndpoints>, group_shares: &[GroupShares]) {
+    let mut applied = Vec::new();
+    for gs in group_shares.iter().filter(|gs| !gs.shares.is_empty()) {
+        let has_shares = lles
+            .iter()
+            .flat_map(|lle| lle.lb_endpoints.iter())
+            .any(|le| gs.share_of(le) > 0);
+        if !has_shares {
+            continue;
+        }
+        for lle in lles.iter_mut() {
+            lle.lb_endpoints.retain(|le| gs.share_of(le) > 0);
+        }
+        applied.push(gs);
+    }
+    if applied.is_empty() {
+        return;
+    }
+    lles.retain(|lle| !lle.lb_endpoints.is_empty());
+
+    let mut priorities: Vec<u32> = lles.iter().map(|lle| lle.priority).collect();
+    priorities.sort_unstable();
+    priorities.dedup();
+    for priority in priorities {
+        let mut endpoints: Vec<&mut LbEndpoint> = lles
+            .iter_mut()
+            .filter(|lle| lle.priority == priority)
+            .flat_map(|lle| lle.lb_endpoints.iter_mut())
+            .collect();
+        let mut weights: Vec<f64> = endpoints
+            .iter()
+            .map(|le| registered_weight(le) as f64)
+            .collect();
+        // Computed all at once in floating point, since integer weights scaled by one share after
+        // another overflow. The result only depends on the endpoints, so it is as stable as they
+        // are.
+        for gs in applied.iter() {
+            let mut group_totals: HashMap<Option<String>, f64> = HashMap::new();
+            for (le, w) in endpoints.iter().zip(weights.iter()) {
+                *group_totals.entry(gs.group_of(le)).or_insert(0.0) += w;
+            }
+            for (le, w) in endpoints.iter().zip(weights.iter_mut()) {
+                *w *= f64::from(gs.share_of(le)) / group_totals[&gs.group_of(le)];
+            }
+        }
+        let total: f64 = weights.iter().sum();
+        for (le, w) in endpoints.iter_mut().zip(weights) {
+            let scaled = (w / total * SHARED_WEIGHT_TOTAL).round() as u32;
+            le.load_balancing_weight = Some(scaled.max(1));
+        }
+    }
+}
+
+fn lb_filter_metadata(le: &LbEndpoint) -> Option<&LbFilterMetadata> {
+    match le.metadata.filter_metadata.get("envoy.lb") {
+        Some(FilterMetadata::Lb(m)) => Some(m),
+        _ => None,
+    }
+}
+
+// Envoy treats endpoints without a weight as weight 1.
+fn registered_weight(le: &LbEndpoint) -> u32 {
+    le.load_balancing_weight.unwrap_or(1)
+}
+
+// Derives version_info from the content of the resources, so that Envoy sees the same version
+// as long as the resources are unchanged. SHA-256 is stable across builds and Rust versions, so
+// instances running different binaries during a deployment agree on the version too.
+pub fn build_version_info<T: serde::Serialize>(
+    resources: &[T],
+) -> Result<String, serde_json::Error> {
+    let json = serde_json::to_string(resources)?;
+    Ok(hex::encode(Sha256::digest(json.as_bytes())))
+}
+
+fn convert_host_to_le(h: Host, c: &EdsConfig) -> LbEndpoint {
+    let mut filter_metadata = BTreeMap::new();
+    if let Some(ref namespace) = c.labels_namespace {
+        filter_metadata.insert(
+            namespace.to_owned(),
+            FilterMetadata::Labels(h.tags.labels.clone()),
+        );
+    }
     filter_metadata.insert(
         "envoy.lb".to_owned(),
-        LbFilterMetadata {
+        FilterMetadata::Lb(LbFilterMetadata {
             canary: h.tags.canary,
             revision: h.revision,
             instance_id: h.tags.instance_id,
-        },
+            labels: h.tags.labels,
+        }),
     );
 
     LbEndpoint {
-        load_balancing_weight: h.tags.load_balancing_weight,
+        health_status: h.health_status,
+        load_balancing_weight: h.tags.load_balancing_weight.map(u32::from),
         metadata: Metadata { filter_metadata },
         endpoint: Endpoint {
             address: Address {
@@ -145,3 +410,150 @@ fn convert_host_to_le(h: Host) -> LbEndpoint {
         },
     }
 }
+
+// Builds an EDS cluster whose endpoints are served by sds itself.
+pub fn build_eds_cluster(name: &str, c: &CdsConfig, locality_weighted_lb: bool) -> Cluster {
+    let overrides = c.services.get(name);
+    let connect_timeout = overrides
+        .and_then(|o| o.connect_timeout.clone())
+        .or_else(|| c.defaults.connect_timeout.clone())
+        .unwrap_or_else(|| "0.25s".to_owned());
+    let lb_policy = overrides
+        .and_then(|o| o.lb_policy.clone())
+        .or_else(|| c.defaults.lb_policy.clone())
+        .unwrap_or_else(|| "ROUND_ROBIN".to_owned());
+
+    Cluster {
+        type_url: CDS_TYPE_URL.to_string(),
+        name: name.to_owned(),
+        discovery_type: "EDS".to_owned(),
+        connect_timeout,
+        lb_policy,
+        eds_cluster_config: EdsClusterConfig {
+            eds_config: ConfigSource {
+                api_config_source: ApiConfigSource {
+                    api_type: "REST".to_owned(),
+                    cluster_names: vec![c.eds_cluster_name.to_owned()],
+                    refresh_delay: c.refresh_delay.to_owned(),
+                },
+            },
+            service_name: name.to_owned(),
+        },
+        common_lb_config: if locality_weighted_lb {
+            Some(CommonLbConfig {
+                locality_weighted_lb_config: LocalityWeightedLbConfig {},
+            })
+        } else {
+            None
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::Tag;
+
+    fn build_host(i: usize, revision: &str, canary: bool, weight: u8) -> Host {
+        Host {
+            ip_address: format!("10.0.{}.{}", i / 256, i % 256),
+            port: 80,
+            last_check_in: String::new(),
+            expire_time: 0,
+            revision: revision.to_owned(),
+            service: "user".to_owned(),
+            tags: Tag {
+                az: "ap-northeast-1a".to_owned(),
+                region: "ap-northeast-1".to_owned(),
+                instance_id: format!("i-{}", i),
+                canary,
+                load_balancing_weight: Some(weight),
+                labels: BTreeMap::new(),
+            },
+            health_status: HealthStatus::Healthy,
+        }
+    }
+
+    // The sum of weights of each group, with the total of the locality.
+    fn group_weights(
+        lles: &[LocalityLbEndpoints],
+        group_of: fn(&LbFilterMetadata) -> String,
+    ) -> (HashMap<String, u64>, u64) {
+        let mut groups = HashMap::new();
+        let mut total = 0;
+        for le in lles.iter().flat_map(|lle| lle.lb_endpoints.iter()) {
+            let w = u64::from(le.load_balancing_weight.unwrap());
+            *groups
+                .entry(group_of(lb_filter_metadata(le).unwrap()))
+                .or_insert(0) += w;
+            total += w;
+        }
+        (groups, total)
+    }
+
+    #[test]
+    fn version_info_is_stable_across_builds() {
+        // The SHA-256 of `["user"]`.
+        assert_eq!(
+            build_version_info(&["user"]).unwrap(),
+            "cec35d191f4a2ca2e8d72e0d763c69a3ab0876b05292f7accca6d7511ab9baf0"
+        );
+    }
+
+    #[test]
+    fn apply_revision_weights_and_canary_percentage() {
+        // Revision "b" has canary hosts only.
+        let hosts = (0..100)
+            .map(|i| match i {
+                0..=79 => build_host(i, "a", false, 100),
+                _ => build_host(i, "b", true, 100),
+            })
+            .collect();
+        let mut lles = hosts_to_locality_lb_endpoints(hosts, None, &EdsConfig::default());
+        let weights: RevisionWeights = vec![("a".to_owned(), 9000), ("b".to_owned(), 1000)]
+            .into_iter()
+            .collect();
+        apply_group_shares(
+            &mut lles,
+            &[GroupShares::revisions(&weights), GroupShares::canary(10)],
+        );
+
+        let (canary, total) = group_weights(&lles, |m| m.canary.to_string());
+        assert!(total <= u64::from(u32::MAX));
+        assert_eq!(canary["true"] * 10 / total, 1);
+        assert_eq!(canary["false"] * 10 / total, 9);
+    }
+
+    #[test]
+    fn keep_locality_sums_within_u32() {
+        let hosts = (0..100)
+            .map(|i| build_host(i, ["a", "b"][i % 2], i % 10 == 0, 100))
+            .collect();
+        let mut lles = hosts_to_locality_lb_endpoints(hosts, None, &EdsConfig::default());
+        let weights: RevisionWeights = vec![("a".to_owned(), 9000), ("b".to_owned(), 1000)]
+            .into_iter()
+            .collect();
+        apply_group_shares(
+            &mut lles,
+            &[GroupShares::revisions(&weights), GroupShares::canary(10)],
+        );
+
+        let (canary, total) = group_weights(&lles, |m| m.canary.to_string());
+        assert!(total <= u64::from(u32::MAX));
+        assert_eq!(canary["true"] * 10 / total, 1);
+    }
+
+    #[test]
+    fn ignore_shares_of_absent_groups() {
+        let hosts = (0..4).map(|i| build_host(i, "a", false, 1)).collect();
+        let mut lles = hosts_to_locality_lb_endpoints(hosts, None, &EdsConfig::default());
+        let weights: RevisionWeights = vec![("b".to_owned(), 100)].into_iter().collect();
+        apply_group_shares(&mut lles, &[GroupShares::revisions(&weights)]);
+
+        assert_eq!(lles[0].lb_endpoints.len(), 4);
+        assert!(lles[0]
+            .lb_endpoints
+            .iter()
+            .all(|le| le.load_balancing_weight == Some(1)));
+    }
+}
@@ -0,0 +1,10 @@
+// The JSON shapes of the v3 EDS messages sds uses are the same as the v2 ones; only the type
+// URLs differ. So the v2 types and the host-to-locality grouping are shared here.
+pub use super::v2xds::{
+    apply_group_shares, build_version_info, hosts_to_locality_lb_endpoints, Address,
+    ClusterLoadAssignment, DiscoveryRequest, EdsDiscoveryResponse, Endpoint, FilterMetadata,
+    GroupShares, LbEndpoint, LbFilterMetadata, Locality, LocalityLbEndpoints, Metadata, Node,
+    SocketAddress, Status,
+};
+
+pub const EDS_TYPE_URL: &str = "type.googleapis.com/envoy.config.endpoint.v3.ClusterLoadAssignment";
@@ -0,0 +1,290 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use futures::future;
+use log::warn;
+use tokio::sync::broadcast;
+use tokio::task::AbortHandle;
+
+use super::types::{HealthStatus, Host, RevisionWeights, ServiceSummary, Storage, StorageFuture};
+
+// Changes are dropped for receivers lagging further behind, which then rebuild every stream.
+const CHANGES_CAPACITY: usize = 1024;
+
+pub const DEFAULT_POLL_INTERVAL_MS: u64 = 1000;
+
+// Publishes the key of every service whose hosts or weights are written through this instance,
+// so that EDS streams can push the change at once. Changes made through other instances or by
+// expiration are found by polling each watched key, once however many streams watch it.
+#[derive(Clone)]
+pub struct WatchedStorage<S: Storage> {
+    inner: S,
+    changes: broadcast::Sender<String>,
+    poll_interval: Duration,
+    polls: Arc<Mutex<HashMap<String, Poll>>>,
+}
+
+// The poller of a key and the count of watches sharing it.
+struct Poll {
+    watches: usize,
+    task: AbortHandle,
+}
+
+// Keeps the key polled while alive.
+pub struct Watch {
+    key: String,
+    polls: Arc<Mutex<HashMap<String, Poll>>>,
+}
+
+impl Drop for Watch {
+    fn drop(&mut self) {
+        let mut polls = lock(&self.polls);
+        if let Some(poll) = polls.get_mut(&self.key) {
+            poll.watches -= 1;
+            if poll.watches == 0 {
+                poll.task.abort();
+                polls.remove(&self.key);
+            }
+        }
+    }
+}
+
+impl<S: Storage> WatchedStorage<S> {
+    pub fn new(inner: S, poll_interval: Duration) -> WatchedStorage<S> {
+        let (changes, _) = broadcast::channel(CHANGES_CAPACITY);
+        WatchedStorage {
+            inner,
+            changes,
+            poll_interval,
+            polls: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<String> {
+        self.changes.subscribe()
+    }
+
+    // Starts polling the key unless it is already polled for another watch.
+    pub fn watch(&self, key: &str) -> Watch {
+        let mut polls = lock(&self.polls);
+        match polls.get_mut(key) {
+            Some(poll) => poll.watches += 1,
+            None => {
+                let task = tokio::spawn(self.clone().poll(key.to_owned()));
+                polls.insert(
+                    key.to_owned(),
+                    Poll {
+                        watches: 1,
+                        task: task.abort_handle(),
+                    },
+                );
+            }
+        }
+        Watch {
+            key: key.to_owned(),
+            polls: self.polls.clone(),
+        }
+    }
+
+    // Publishes the key whenever its content differs from the previous poll, and after the
+    // storage recovers from failures, which may have failed the responses of streams too.
+    async fn poll(self, key: String) {
+        let mut interval = tokio::time::interval(self.poll_interval);
+        let mut last = None;
+        let mut failed = false;
+        loop {
+            interval.tick().await;
+            let res = future::try_join(
+                self.inner.query_items(&key),
+                self.inner.query_revision_weights(&key),
+            )
+            .await;
+            let content = match res {
+                Ok((hosts, weights)) => build_content(hosts, &weights),
+                // Retried by the next poll.
+                Err(e) => {
+                    warn!("Failed to poll: key={}, error={}", key, e);
+                    failed = true;
+                    continue;
+                }
+            };
+            if failed || last.as_ref().is_some_and(|last| *last != content) {
+                // Fails only when no stream is open.
+                let _ = self.changes.send(key.to_owned());
+            }
+            last = Some(content);
+            failed = false;
+        }
+    }
+
+    fn publish_after<T: Send + 'static>(
+        &self,
+        name: &str,
+        f: StorageFuture<T, S::E>,
+    ) -> StorageFuture<T, S::E> {
+        let changes = self.changes.clone();
+        let name = name.to_owned();
+        Box::pin(async move {
+            let res = f.await;
+            if res.is_ok() {
+                // Fails only when no stream is open.
+                let _ = changes.send(name);
+            }
+            res
+        })
+    }
+}
+
+impl<S: Storage> Storage for WatchedStorage<S> {
+    type E = S::E;
+
+    fn query_items(&self, name: &str) -> StorageFuture<Vec<Host>, Self::E> {
+        self.inner.query_items(name)
+    }
+
+    fn store_item(&self, name: &str, host: Host) -> StorageFuture<(), Self::E> {
+        self.publish_after(name, self.inner.store_item(name, host))
+    }
+
+    fn delete_item(
+        &self,
+        name: &str,
+        ip: String,
+        port: u64,
+    ) -> StorageFuture<Option<Host>, Self::E> {
+        self.publish_after(name, self.inner.delete_item(name, ip, port))
+    }
+
+    // Heartbeats only extend the TTL, which is not part of EDS responses.
+    fn refresh_item(
+        &self,
+        name: &str,
+        ip: String,
+        port: u64,
+        last_check_in: String,
+        expire_time: u64,
+    ) -> StorageFuture<Option<Host>, Self::E> {
+        self.inner
+            .refresh_item(name, ip, port, last_check_in, expire_time)
+    }
+
+    fn update_status(
+        &self,
+        name: &str,
+        ip: String,
+        port: u64,
+        status: HealthStatus,
+    ) -> StorageFuture<Option<Host>, Self::E> {
+        self.publish_after(name, self.inner.update_status(name, ip, port, status))
+    }
+
+    fn list_services(&self) -> StorageFuture<Vec<ServiceSummary>, Self::E> {
+        self.inner.list_services()
+    }
+
+    fn query_revision_weights(&self, name: &str) -> StorageFuture<RevisionWeights, Self::E> {
+        self.inner.query_revision_weights(name)
+    }
+
+    fn store_revision_weights(
+        &self,
+        name: &str,
+        weights: RevisionWeights,
+    ) -> StorageFuture<(), Self::E> {
+        self.publish_after(name, self.inner.store_revision_weights(name, weights))
+    }
+
+    fn ttl(&self) -> u64 {
+        self.inner.ttl()
+    }
+}
+
+// Leaves out when hosts checked in, so that heartbeats are not taken for changes.
+fn build_content(mut hosts: Vec<Host>, weights: &RevisionWeights) -> String {
+    for h in hosts.iter_mut() {
+        h.last_check_in.clear();
+        h.expire_time = 0;
+    }
+    hosts.sort_by(|a, b| (&a.ip_address, a.port).cmp(&(&b.ip_address, b.port)));
+    serde_json::json!({ "hosts": hosts, "weights": weights }).to_string()
+}
+
+// A panic while holding a lock leaves nothing half-updated here, so recover from poisoning.
+fn lock<T>(m: &Mutex<T>) -> MutexGuard<'_, T> {
+    m.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use super::*;
+    use crate::memory::MemoryStorage;
+    use crate::types::Tag;
+
+    fn build_host(ip_address: &str, last_check_in: &str) -> Host {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        Host {
+            ip_address: ip_address.to_owned(),
+            port: 80,
+            last_check_in: last_check_in.to_owned(),
+            expire_time: now + 60,
+            revision: "abc".to_owned(),
+            service: "user".to_owned(),
+            tags: Tag {
+                az: "ap-northeast-1a".to_owned(),
+                region: "ap-northeast-1".to_owned(),
+                instance_id: "i-1".to_owned(),
+                canary: false,
+                load_balancing_weight: None,
+                labels: BTreeMap::new(),
+            },
+            health_status: HealthStatus::Healthy,
+        }
+    }
+
+    #[tokio::test]
+    async fn share_pollers_among_watches() {
+        let s = WatchedStorage::new(MemoryStorage::new(60), Duration::from_millis(10));
+        let first = s.watch("user");
+        let second = s.watch("user");
+        let other = s.watch("payment");
+        assert_eq!(lock(&s.polls)["user"].watches, 2);
+
+        drop(first);
+        assert_eq!(lock(&s.polls)["user"].watches, 1);
+        drop(second);
+        drop(other);
+        assert!(lock(&s.polls).is_empty());
+    }
+
+    #[tokio::test]
+    async fn publish_changes_found_by_polling() {
+        let memory = MemoryStorage::new(60);
+        let s = WatchedStorage::new(memory.clone(), Duration::from_millis(10));
+        let mut changes = s.subscribe();
+        let _watch = s.watch("user");
+        tokio::time::sleep(Duration::from_millis(30)).await;
+
+        // Written through another instance sharing the storage.
+        memory
+            .store_item("user", build_host("10.0.0.1", "2026-01-01T00:00:00Z"))
+            .await
+            .unwrap();
+        let key = tokio::time::timeout(Duration::from_secs(1), changes.recv()).await;
+        assert_eq!(key.unwrap().unwrap(), "user");
+
+        // Heartbeats are not changes.
+        memory
+            .store_item("user", build_host("10.0.0.1", "2026-01-01T00:00:30Z"))
+            .await
+            .unwrap();
+        let key = tokio::time::timeout(Duration::from_millis(100), changes.recv()).await;
+        assert!(key.is_err());
+    }
+}
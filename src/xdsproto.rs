@@ -0,0 +1,295 @@
+// Protobuf encodings of the xDS messages sds streams over gRPC. Only the fields sds reads or
+// writes are declared; prost skips the others. The v2 and v3 messages share field numbers, so
+// these serve both, and the type URL of each resource tells Envoy which one it is.
+use std::collections::BTreeMap;
+
+use prost::Message;
+use prost_types::value::Kind;
+use prost_types::{Any, ListValue, Struct, Value};
+
+use super::types::HealthStatus;
+use super::v2xds;
+
+#[derive(Clone, PartialEq, Message)]
+pub struct DiscoveryRequest {
+    #[prost(string, tag = "1")]
+    pub version_info: String,
+    #[prost(message, optional, tag = "2")]
+    pub node: Option<Node>,
+    #[prost(string, repeated, tag = "3")]
+    pub resource_names: Vec<String>,
+    #[prost(string, tag = "4")]
+    pub type_url: String,
+    #[prost(string, tag = "5")]
+    pub response_nonce: String,
+    #[prost(message, optional, tag = "6")]
+    pub error_detail: Option<Status>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct DiscoveryResponse {
+    #[prost(string, tag = "1")]
+    pub version_info: String,
+    #[prost(message, repeated, tag = "2")]
+    pub resources: Vec<Any>,
+    #[prost(string, tag = "4")]
+    pub type_url: String,
+    #[prost(string, tag = "5")]
+    pub nonce: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Node {
+    #[prost(string, tag = "1")]
+    pub id: String,
+    #[prost(string, tag = "2")]
+    pub cluster: String,
+    #[prost(message, optional, tag = "4")]
+    pub locality: Option<Locality>,
+}
+
+// google.rpc.Status
+#[derive(Clone, PartialEq, Message)]
+pub struct Status {
+    #[prost(int32, tag = "1")]
+    pub code: i32,
+    #[prost(string, tag = "2")]
+    pub message: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Locality {
+    #[prost(string, tag = "1")]
+    pub region: String,
+    #[prost(string, tag = "2")]
+    pub zone: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct ClusterLoadAssignment {
+    #[prost(string, tag = "1")]
+    pub cluster_name: String,
+    #[prost(message, repeated, tag = "2")]
+    pub endpoints: Vec<LocalityLbEndpoints>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct LocalityLbEndpoints {
+    #[prost(message, optional, tag = "1")]
+    pub locality: Option<Locality>,
+    #[prost(message, repeated, tag = "2")]
+    pub lb_endpoints: Vec<LbEndpoint>,
+    // google.protobuf.UInt32Value
+    #[prost(message, optional, tag = "3")]
+    pub load_balancing_weight: Option<u32>,
+    #[prost(uint32, tag = "5")]
+    pub priority: u32,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct LbEndpoint {
+    #[prost(message, optional, tag = "1")]
+    pub endpoint: Option<Endpoint>,
+    // core.HealthStatus
+    #[prost(int32, tag = "2")]
+    pub health_status: i32,
+    #[prost(message, optional, tag = "3")]
+    pub metadata: Option<Metadata>,
+    // google.protobuf.UInt32Value
+    #[prost(message, optional, tag = "4")]
+    pub load_balancing_weight: Option<u32>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Endpoint {
+    #[prost(message, optional, tag = "1")]
+    pub address: Option<Address>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Address {
+    #[prost(message, optional, tag = "1")]
+    pub socket_address: Option<SocketAddress>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct SocketAddress {
+    #[prost(string, tag = "2")]
+    pub address: String,
+    #[prost(uint32, tag = "3")]
+    pub port_value: u32,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Metadata {
+    #[prost(btree_map = "string, message", tag = "1")]
+    pub filter_metadata: BTreeMap<String, Struct>,
+}
+
+impl From<Locality> for v2xds::Locality {
+    fn from(l: Locality) -> v2xds::Locality {
+        v2xds::Locality {
+            region: l.region,
+            zone: l.zone,
+        }
+    }
+}
+
+impl From<&v2xds::Locality> for Locality {
+    fn from(l: &v2xds::Locality) -> Locality {
+        Locality {
+            region: l.region.to_owned(),
+            zone: l.zone.to_owned(),
+        }
+    }
+}
+
+impl ClusterLoadAssignment {
+    // Converts the assignment built for the REST API, so that both transports serve the same
+    // endpoints.
+    pub fn from_json(cla: &v2xds::ClusterLoadAssignment) -> Result<Self, serde_json::Error> {
+        let mut endpoints = Vec::new();
+        for lle in cla.endpoints.iter() {
+            let mut lb_endpoints = Vec::new();
+            for le in lle.lb_endpoints.iter() {
+                let mut filter_metadata = BTreeMap::new();
+                for (namespace, fm) in le.metadata.filter_metadata.iter() {
+                    filter_metadata
+                        .insert(namespace.to_owned(), to_struct(serde_json::to_value(fm)?));
+                }
+                let socket_address = &le.endpoint.address.socket_address;
+                lb_endpoints.push(LbEndpoint {
+                    endpoint: Some(Endpoint {
+                        address: Some(Address {
+                            socket_address: Some(SocketAddress {
+                                address: socket_address.address.to_owned(),
+                                port_value: u32::from(socket_address.port_value),
+                            }),
+                        }),
+                    }),
+                    health_status: health_status_number(le.health_status),
+                    metadata: Some(Metadata { filter_metadata }),
+                    load_balancing_weight: le.load_balancing_weight,
+                });
+            }
+            endpoints.push(LocalityLbEndpoints {
+                locality: Some(Locality::from(&lle.locality)),
+                lb_endpoints,
+                load_balancing_weight: lle.load_balancing_weight,
+                priority: lle.priority,
+            });
+        }
+        Ok(ClusterLoadAssignment {
+            cluster_name: cla.cluster_name.to_owned(),
+            endpoints,
+        })
+    }
+
+    pub fn to_any(&self, type_url: &str) -> Any {
+        Any {
+            type_url: type_url.to_owned(),
+            value: self.encode_to_vec(),
+        }
+    }
+}
+
+fn health_status_number(status: HealthStatus) -> i32 {
+    match status {
+        HealthStatus::Healthy => 1,
+        HealthStatus::Unhealthy => 2,
+        HealthStatus::Draining => 3,
+        HealthStatus::Degraded => 5,
+    }
+}
+
+// Filter metadata are JSON objects, which are google.protobuf.Struct in protobuf.
+fn to_struct(v: serde_json::Value) -> Struct {
+    match to_value(v).kind {
+        Some(Kind::StructValue(s)) => s,
+        _ => Struct::default(),
+    }
+}
+
+fn to_value(v: serde_json::Value) -> Value {
+    let kind = match v {
+        serde_json::Value::Null => Kind::NullValue(0),
+        serde_json::Value::Bool(b) => Kind::BoolValue(b),
+        serde_json::Value::Number(n) => Kind::NumberValue(n.as_f64().unwrap_or_default()),
+        serde_json::Value::String(s) => Kind::StringValue(s),
+        serde_json::Value::Array(vs) => Kind::ListValue(ListValue {
+            values: vs.into_iter().map(to_value).collect(),
+        }),
+        serde_json::Value::Object(m) => Kind::StructValue(Struct {
+            fields: m.into_iter().map(|(k, v)| (k, to_value(v))).collect(),
+        }),
+    };
+    Value { kind: Some(kind) }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn encode_cluster_load_assignment() {
+        let cla: v2xds::ClusterLoadAssignment = serde_json::from_value(json!({
+            "@type": v2xds::EDS_TYPE_URL,
+            "cluster_name": "team-a/user",
+            "endpoints": [{
+                "locality": {"region": "ap-northeast-1", "zone": "ap-northeast-1a"},
+                "load_balancing_weight": 10,
+                "priority": 1,
+                "lb_endpoints": [{
+                    "endpoint": {"address": {"socket_address": {"address": "10.0.0.1", "port_value": 8080}}},
+                    "health_status": "DRAINING",
+                    "metadata": {"filter_metadata": {"envoy.lb": {
+                        "canary": true,
+                        "revision": "abc",
+                        "instance_id": "i-1",
+                        "weight": 3,
+                    }}},
+                    "load_balancing_weight": 20,
+                }],
+            }],
+        }))
+        .unwrap();
+
+        let any = ClusterLoadAssignment::from_json(&cla)
+            .unwrap()
+            .to_any(v2xds::EDS_TYPE_URL);
+        assert_eq!(any.type_url, v2xds::EDS_TYPE_URL);
+        let decoded = ClusterLoadAssignment::decode(any.value.as_slice()).unwrap();
+        assert_eq!(decoded.cluster_name, "team-a/user");
+        let lle = &decoded.endpoints[0];
+        assert_eq!(
+            lle.locality,
+            Some(Locality {
+                region: "ap-northeast-1".to_owned(),
+                zone: "ap-northeast-1a".to_owned(),
+            })
+        );
+        assert_eq!(lle.load_balancing_weight, Some(10));
+        assert_eq!(lle.priority, 1);
+
+        let le = &lle.lb_endpoints[0];
+        let socket_address = le
+            .endpoint
+            .as_ref()
+            .and_then(|e| e.address.as_ref())
+            .and_then(|a| a.socket_address.as_ref())
+            .unwrap();
+        assert_eq!(socket_address.address, "10.0.0.1");
+        assert_eq!(socket_address.port_value, 8080);
+        assert_eq!(le.health_status, 3);
+        assert_eq!(le.load_balancing_weight, Some(20));
+        let lb = &le.metadata.as_ref().unwrap().filter_metadata["envoy.lb"];
+        assert_eq!(lb.fields["canary"].kind, Some(Kind::BoolValue(true)));
+        assert_eq!(
+            lb.fields["revision"].kind,
+            Some(Kind::StringValue("abc".to_owned()))
+        );
+        assert_eq!(lb.fields["weight"].kind, Some(Kind::NumberValue(3.0)));
+    }
+}